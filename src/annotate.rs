@@ -0,0 +1,252 @@
+//! GFF3-based functional annotation of variable sites.
+//!
+//! Maps each variable position (via its ungapped reference coordinate) onto CDS
+//! features and predicts codon and amino acid changes with the bacterial
+//! translation table (NCBI table 11). Single-segment CDSs are assumed, as in
+//! bacterial annotations; the reference codon context comes from the first sequence.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::types::VariablePosition;
+
+/// A CDS feature from a GFF3 file (0-based, inclusive coordinates).
+pub struct CdsFeature {
+    pub id: String,
+    pub start: usize,
+    pub end: usize,
+    pub strand: u8,
+    /// Bases before the first complete codon, in coding orientation (GFF3 phase).
+    pub phase: usize,
+}
+
+/// Predicted effect of one ALT allele.
+pub struct AltEffect {
+    pub codon: [u8; 3],
+    pub aa: u8,
+    pub consequence: &'static str,
+}
+
+/// Annotation of a variable site that falls within a CDS.
+pub struct SiteAnnotation {
+    /// Index into the CDS feature list.
+    pub cds: usize,
+    /// Codon position (1, 2 or 3) in coding orientation.
+    pub codon_pos: u8,
+    /// Reference codon in coding orientation; `None` for an incomplete terminal codon.
+    pub ref_codon: Option<[u8; 3]>,
+    /// One effect per ALT allele (empty if the codon is incomplete).
+    pub alts: Vec<AltEffect>,
+}
+
+/// CDS features together with per-site annotations (parallel to variable positions).
+pub struct Annotations {
+    pub cds: Vec<CdsFeature>,
+    pub sites: Vec<Option<SiteAnnotation>>,
+}
+
+/// Amino acids of NCBI translation table 11, codons ordered TCAG × TCAG × TCAG.
+const TABLE_11: &[u8; 64] = b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG";
+
+fn base_index(b: u8) -> Option<usize> {
+    match b.to_ascii_uppercase() {
+        b'T' => Some(0), b'C' => Some(1), b'A' => Some(2), b'G' => Some(3),
+        _ => None,
+    }
+}
+
+/// Translate a codon with table 11; `X` for codons containing non-ACGT bases.
+pub fn translate(codon: &[u8; 3]) -> u8 {
+    match (base_index(codon[0]), base_index(codon[1]), base_index(codon[2])) {
+        (Some(a), Some(b), Some(c)) => TABLE_11[a * 16 + b * 4 + c],
+        _ => b'X',
+    }
+}
+
+/// Watson-Crick complement (case-folded); non-ACGT bases map to themselves.
+pub fn complement(b: u8) -> u8 {
+    match b.to_ascii_uppercase() {
+        b'A' => b'T', b'C' => b'G', b'G' => b'C', b'T' => b'A',
+        o => o,
+    }
+}
+
+fn consequence(ref_aa: u8, alt_aa: u8) -> &'static str {
+    if ref_aa == b'X' || alt_aa == b'X' { "unknown" }
+    else if ref_aa == alt_aa && ref_aa == b'*' { "stop_retained_variant" }
+    else if ref_aa == alt_aa { "synonymous_variant" }
+    else if alt_aa == b'*' { "stop_gained" }
+    else if ref_aa == b'*' { "stop_lost" }
+    else { "missense_variant" }
+}
+
+/// Pick a feature ID from GFF3 attributes: `locus_tag`, `gene`, `Name`, then `ID`.
+fn feature_id(attrs: &str) -> Option<String> {
+    for key in ["locus_tag", "gene", "Name", "ID"] {
+        for kv in attrs.split(';') {
+            if let Some((k, v)) = kv.trim().split_once('=') {
+                if k == key { return Some(v.to_string()); }
+            }
+        }
+    }
+    None
+}
+
+/// Read CDS features from a GFF3 file, sorted by start.
+///
+/// Features are taken from sequence `seqid`; without it, all CDSs must lie on a
+/// single sequence. Coordinates must fit a reference of `ref_len` bases.
+pub fn read_gff(path: &str, seqid: Option<&str>, ref_len: usize) -> io::Result<Vec<CdsFeature>> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open GFF '{}': {}", path, e)))?;
+    let bad = |ln: usize, msg: &str| io::Error::new(io::ErrorKind::InvalidData,
+        format!("GFF '{}' line {}: {}", path, ln, msg));
+
+    let mut cds = Vec::new();
+    let mut seqids: Vec<String> = Vec::new();
+    for (li, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.starts_with("##FASTA") { break; }
+        if line.starts_with('#') || line.trim().is_empty() { continue; }
+        let f: Vec<&str> = line.split('\t').collect();
+        if f.len() < 9 { return Err(bad(li + 1, "expected 9 columns")); }
+        if f[2] != "CDS" { continue; }
+        match seqid {
+            Some(s) if s != f[0] => continue,
+            Some(_) => {}
+            None => if !seqids.iter().any(|s| s == f[0]) { seqids.push(f[0].to_string()); },
+        }
+        let start: usize = f[3].parse().map_err(|_| bad(li + 1, "invalid start"))?;
+        let end: usize = f[4].parse().map_err(|_| bad(li + 1, "invalid end"))?;
+        if start == 0 || end < start { return Err(bad(li + 1, "invalid coordinates")); }
+        if end > ref_len {
+            return Err(bad(li + 1, &format!("CDS end {} is past the reference length {}", end, ref_len)));
+        }
+        let strand = match f[6] { "-" => b'-', _ => b'+' };
+        let phase = match f[7] {
+            "." | "0" => 0, "1" => 1, "2" => 2,
+            _ => return Err(bad(li + 1, "invalid phase")),
+        };
+        let id = feature_id(f[8]).unwrap_or_else(|| format!("cds_{}_{}", start, end));
+        cds.push(CdsFeature { id, start: start - 1, end: end - 1, strand, phase });
+    }
+    if seqids.len() > 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "GFF '{}' has CDS features on {} sequences ({}); choose one with --gff-seqid.",
+            path, seqids.len(), seqids.join(", "))));
+    }
+    cds.sort_by_key(|c| (c.start, c.end));
+    Ok(cds)
+}
+
+/// Index of the first CDS (lowest start) covering ungapped coordinate `rp`.
+pub fn find_cds(cds: &[CdsFeature], max_len: usize, rp: usize) -> Option<usize> {
+    let hi = cds.partition_point(|c| c.start <= rp);
+    let lo = cds[..hi].partition_point(|c| c.start + max_len < rp);
+    (lo..hi).find(|&i| cds[i].end >= rp)
+}
+
+/// Codon position (0-based) of `rp` in `c`, and the genomic positions of the
+/// codon's bases in coding order, or `None` if the codon runs past either end of
+/// the feature (including the partial codon before the first `phase` bases).
+pub fn codon_at(c: &CdsFeature, rp: usize) -> (usize, Option<[usize; 3]>) {
+    if c.strand == b'+' {
+        let cp = (rp - c.start + 3 - c.phase) % 3;
+        let s = rp.checked_sub(cp).filter(|&s| s >= c.start);
+        (cp, s.filter(|&s| s + 2 <= c.end).map(|s| [s, s + 1, s + 2]))
+    } else {
+        let cp = (c.end - rp + 3 - c.phase) % 3;
+        let s = Some(rp + cp).filter(|&s| s <= c.end);
+        (cp, s.filter(|&s| s >= c.start + 2).map(|s| [s, s - 1, s - 2]))
+    }
+}
+
+/// Annotate variable positions against CDS features.
+///
+/// `ref_ungapped` is the reference sequence with gaps removed. Sites outside any
+/// CDS, or at columns where the reference has a gap, are `None`.
+pub fn annotate(
+    var_positions: &[VariablePosition], cds: Vec<CdsFeature>, ref_ungapped: &[u8],
+) -> Annotations {
+    let max_len = cds.iter().map(|c| c.end - c.start).max().unwrap_or(0);
+    let sites = var_positions.iter().map(|vp| {
+        let rp = vp.ref_pos?;
+        let ci = find_cds(&cds, max_len, rp)?;
+        let c = &cds[ci];
+        let (cp, genomic) = codon_at(c, rp);
+        let orient = |b: u8| if c.strand == b'+' { b.to_ascii_uppercase() } else { complement(b) };
+
+        let ref_codon = genomic.filter(|g| g[2].max(g[0]) < ref_ungapped.len())
+            .map(|g| [orient(ref_ungapped[g[0]]), orient(ref_ungapped[g[1]]), orient(ref_ungapped[g[2]])]);
+        let alts = match ref_codon {
+            Some(rc) => {
+                let ref_aa = translate(&rc);
+                vp.alt_bases.iter().map(|&ab| {
+                    if ab == b'-' {
+                        return AltEffect { codon: [b'-'; 3], aa: b'-', consequence: "gap" };
+                    }
+                    let mut codon = rc;
+                    codon[cp] = orient(ab);
+                    let aa = translate(&codon);
+                    AltEffect { codon, aa, consequence: consequence(ref_aa, aa) }
+                }).collect()
+            }
+            None => Vec::new(),
+        };
+        Some(SiteAnnotation { cds: ci, codon_pos: cp as u8 + 1, ref_codon, alts })
+    }).collect();
+    Annotations { cds, sites }
+}
+
+/// VCF `CSQ` value: one `Allele|Gene|Strand|CodonPos|Codons|AminoAcids|Consequence`
+/// entry per ALT, comma-separated.
+pub fn csq_field(a: &SiteAnnotation, vp: &VariablePosition, cds: &[CdsFeature]) -> String {
+    let c = &cds[a.cds];
+    vp.alt_bases.iter().enumerate().map(|(i, &ab)| {
+        let allele = if ab == b'-' { '*' } else { ab as char };
+        match (a.ref_codon, a.alts.get(i)) {
+            (Some(rc), Some(e)) => format!("{}|{}|{}|{}|{}/{}|{}/{}|{}",
+                allele, c.id, c.strand as char, a.codon_pos,
+                String::from_utf8_lossy(&rc), String::from_utf8_lossy(&e.codon),
+                translate(&rc) as char, e.aa as char, e.consequence),
+            _ => format!("{}|{}|{}|{}|||incomplete_terminal_codon_variant",
+                allele, c.id, c.strand as char, a.codon_pos),
+        }
+    }).collect::<Vec<_>>().join(",")
+}
+
+/// Write the annotation report as TSV, one row per ALT allele.
+///
+/// Columns: position, ref_position, ref, alt, gene, strand, codon_pos, codons,
+/// amino_acids, consequence. Intergenic sites use `.` and `intergenic_variant`.
+pub fn write_annotation_tsv(
+    annotations: &Annotations, var_positions: &[VariablePosition], path: &str,
+) -> io::Result<()> {
+    let Annotations { cds, sites } = annotations;
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create annotation report '{}': {}", path, e)))?;
+    let mut w = BufWriter::new(out);
+
+    writeln!(w, "position\tref_position\tref\talt\tgene\tstrand\tcodon_pos\tcodons\tamino_acids\tconsequence")?;
+    for (ann, vp) in sites.iter().zip(var_positions.iter()) {
+        let rp = vp.ref_pos.map_or(".".to_string(), |p| (p + 1).to_string());
+        for (i, &ab) in vp.alt_bases.iter().enumerate() {
+            write!(w, "{}\t{}\t{}\t{}\t", vp.index + 1, rp, vp.ref_base as char, ab as char)?;
+            match ann {
+                Some(a) => {
+                    let c = &cds[a.cds];
+                    write!(w, "{}\t{}\t{}\t", c.id, c.strand as char, a.codon_pos)?;
+                    match (a.ref_codon, a.alts.get(i)) {
+                        (Some(rc), Some(e)) => writeln!(w, "{}/{}\t{}/{}\t{}",
+                            String::from_utf8_lossy(&rc), String::from_utf8_lossy(&e.codon),
+                            translate(&rc) as char, e.aa as char, e.consequence)?,
+                        _ => writeln!(w, ".\t.\tincomplete_terminal_codon_variant")?,
+                    }
+                }
+                None => writeln!(w, ".\t.\t.\t.\t.\tintergenic_variant")?,
+            }
+        }
+    }
+
+    w.flush()
+}
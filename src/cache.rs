@@ -0,0 +1,236 @@
+//! Pass 1 cache for incremental re-runs.
+//!
+//! A sidecar file holding the pass 1 bitmask, the record index and per-record
+//! soft-mask counts. The records may come from several FASTA files: the `--fasta`
+//! input, then each file added with `--append`, which are read in place and never
+//! modified. The cache is tied to each file by its size, modification time,
+//! inode and a digest of its content, and is only reused while all of them are
+//! unchanged. A later run on the same inputs skips indexing and pass 1; with
+//! `--append`, only the new records are indexed and scanned and their classes
+//! ORed into the cached bitmask.
+//!
+//! Layout (little-endian): magic, `seq_length`, flags, source count, then per
+//! source `path_len path len mtime inode digest`, record count, then per record
+//! `source seq_offset line_width line_stride masked`, then the bitmask.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::UNIX_EPOCH;
+
+use crate::fasta::{header_before, index_fasta, FastaRecord};
+use crate::types::SeqLayout;
+
+const MAGIC: &[u8; 8] = b"SNPICKC3";
+
+/// The content digest reads this many evenly spaced blocks of `DIGEST_BLOCK` bytes.
+const DIGEST_BLOCKS: u64 = 256;
+const DIGEST_BLOCK: u64 = 4096;
+
+const FLAG_GAPS: u64 = 1;
+const FLAG_LOWERCASE: u64 = 2;
+const FLAG_SINGLE_LINE: u64 = 4;
+const FLAG_FIXED_WIDTH: u64 = 8;
+
+/// A FASTA file the cached records come from, identified by its metadata and content.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceFile {
+    /// Canonical path (for appended files, which are reopened from it).
+    pub path: String,
+    pub len: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub mtime: u64,
+    /// Inode number (0 where the platform has none).
+    pub inode: u64,
+    /// Hash of the length and of sampled content (see `content_digest`).
+    pub digest: u64,
+}
+
+fn fnv1a(mut h: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes { h = (h ^ b as u64).wrapping_mul(0x100_0000_01b3); }
+    h
+}
+
+/// FNV-1a hash of the file length and its content: all of it for files of up to
+/// `DIGEST_BLOCKS` blocks, otherwise `DIGEST_BLOCKS` evenly spaced blocks from
+/// the first to the last, so that large inputs are not read in full.
+fn content_digest(file: &mut File, len: u64) -> io::Result<u64> {
+    let mut h = fnv1a(0xcbf2_9ce4_8422_2325, &len.to_le_bytes());
+    if len <= DIGEST_BLOCKS * DIGEST_BLOCK {
+        let mut all = Vec::with_capacity(len as usize);
+        file.read_to_end(&mut all)?;
+        return Ok(fnv1a(h, &all));
+    }
+    let stride = (len - DIGEST_BLOCK) / (DIGEST_BLOCKS - 1);
+    let mut block = vec![0u8; DIGEST_BLOCK as usize];
+    for i in 0..DIGEST_BLOCKS {
+        file.seek(SeekFrom::Start(i * stride))?;
+        file.read_exact(&mut block)?;
+        h = fnv1a(h, &block);
+    }
+    Ok(h)
+}
+
+impl SourceFile {
+    /// Identify the file at `path` by its current metadata and content digest.
+    pub fn stat(path: &str) -> io::Result<Self> {
+        let err = |e: io::Error| io::Error::new(e.kind(), format!("Cannot stat '{}': {}", path, e));
+        let canonical = std::fs::canonicalize(path).map_err(err)?;
+        let mut file = File::open(&canonical).map_err(err)?;
+        let meta = file.metadata().map_err(err)?;
+        let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos() as u64);
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&meta);
+        #[cfg(not(unix))]
+        let inode = 0;
+        let digest = content_digest(&mut file, meta.len()).map_err(err)?;
+        Ok(SourceFile { path: canonical.to_string_lossy().into_owned(), len: meta.len(), mtime, inode, digest })
+    }
+
+    /// Whether `now` is the same, unmodified file: the metadata first, then the digest.
+    pub fn unchanged(&self, now: &SourceFile) -> bool {
+        self.len == now.len && self.mtime == now.mtime && self.inode == now.inode && self.digest == now.digest
+    }
+}
+
+/// Where a record's sequence lies in its source file.
+pub struct RecordLoc {
+    /// Index into `Pass1Cache::sources`.
+    pub source: usize,
+    pub seq_offset: usize,
+    pub line_width: usize,
+    pub line_stride: usize,
+    /// Soft-masked calls (with `--lowercase-as-missing`).
+    pub masked: usize,
+}
+
+/// Pass 1 results for a set of input files and one set of scan options.
+pub struct Pass1Cache {
+    pub include_gaps: bool,
+    pub lowercase_as_missing: bool,
+    pub seq_length: usize,
+    pub layout: SeqLayout,
+    /// The `--fasta` input first, then the appended files in order.
+    pub sources: Vec<SourceFile>,
+    pub records: Vec<RecordLoc>,
+    pub bitmask: Vec<u8>,
+}
+
+impl Pass1Cache {
+    /// Whether the cache was built with the same scan options from `fasta` and
+    /// appended files that are all unchanged since.
+    pub fn matches(&self, fasta: &SourceFile, include_gaps: bool, lowercase_as_missing: bool) -> bool {
+        self.include_gaps == include_gaps && self.lowercase_as_missing == lowercase_as_missing
+            && self.sources.first().is_some_and(|s| s.unchanged(fasta))
+            && self.sources[1..].iter().all(|s| SourceFile::stat(&s.path).is_ok_and(|now| s.unchanged(&now)))
+    }
+
+    /// Rebuild the records from their cached offsets into the mapped `sources`
+    /// (in the order of `Pass1Cache::sources`), reading only the headers.
+    pub fn records<'a>(&self, sources: &[&'a [u8]]) -> io::Result<Vec<FastaRecord<'a>>> {
+        self.records.iter().map(|r| {
+            let data = sources.get(r.source).copied().ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData, format!("Cache: unknown source {}.", r.source)))?;
+            let (id, desc) = header_before(data, r.seq_offset).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData, format!("Cache: no header before offset {}.", r.seq_offset)))?;
+            Ok(FastaRecord { data, id, desc, seq_offset: r.seq_offset, line_width: r.line_width, line_stride: r.line_stride })
+        }).collect()
+    }
+}
+
+pub fn write_cache(cache: &Pass1Cache, path: &str) -> io::Result<()> {
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create cache '{}': {}", path, e)))?;
+    let mut w = BufWriter::new(out);
+    let put = |w: &mut BufWriter<File>, v: u64| w.write_all(&v.to_le_bytes());
+
+    let flags = [
+        (cache.include_gaps, FLAG_GAPS), (cache.lowercase_as_missing, FLAG_LOWERCASE),
+        (cache.layout.single_line, FLAG_SINGLE_LINE), (cache.layout.fixed_width, FLAG_FIXED_WIDTH),
+    ].iter().filter(|(on, _)| *on).fold(0, |f, (_, bit)| f | bit);
+    w.write_all(MAGIC)?;
+    for v in [cache.seq_length as u64, flags, cache.sources.len() as u64] {
+        put(&mut w, v)?;
+    }
+    for s in &cache.sources {
+        put(&mut w, s.path.len() as u64)?;
+        w.write_all(s.path.as_bytes())?;
+        for v in [s.len, s.mtime, s.inode, s.digest] { put(&mut w, v)?; }
+    }
+    put(&mut w, cache.records.len() as u64)?;
+    for r in &cache.records {
+        for v in [r.source, r.seq_offset, r.line_width, r.line_stride, r.masked] { put(&mut w, v as u64)?; }
+    }
+    w.write_all(&cache.bitmask)?;
+    w.flush()
+}
+
+pub fn read_cache(path: &str) -> io::Result<Pass1Cache> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open cache '{}': {}", path, e)))?;
+    let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Cache '{}': {}", path, msg));
+    let mut r = BufReader::new(file);
+    let get = |r: &mut BufReader<File>| -> io::Result<u64> {
+        let mut b = [0u8; 8];
+        r.read_exact(&mut b).map_err(|_| bad("truncated"))?;
+        Ok(u64::from_le_bytes(b))
+    };
+    let size = |v: u64| usize::try_from(v).map_err(|_| bad("value out of range"));
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic).map_err(|_| bad("truncated"))?;
+    if &magic != MAGIC { return Err(bad("not a snpick cache (or written by another version)")); }
+    let seq_length = size(get(&mut r)?)?;
+    let flags = get(&mut r)?;
+    let num_sources = size(get(&mut r)?)?;
+    if num_sources == 0 { return Err(bad("no source files")); }
+    let mut sources = Vec::with_capacity(num_sources.min(1 << 10));
+    for _ in 0..num_sources {
+        let name_len = size(get(&mut r)?)?;
+        if name_len > 1 << 16 { return Err(bad("source path too long")); }
+        let mut name = vec![0u8; name_len];
+        r.read_exact(&mut name).map_err(|_| bad("truncated"))?;
+        let path = String::from_utf8(name).map_err(|_| bad("invalid source path"))?;
+        let (len, mtime, inode, digest) = (get(&mut r)?, get(&mut r)?, get(&mut r)?, get(&mut r)?);
+        sources.push(SourceFile { path, len, mtime, inode, digest });
+    }
+    let n = size(get(&mut r)?)?;
+    let mut records = Vec::with_capacity(n.min(1 << 20));
+    for _ in 0..n {
+        let mut v = [0usize; 5];
+        for x in v.iter_mut() { *x = size(get(&mut r)?)?; }
+        if v[0] >= num_sources { return Err(bad("record from an unknown source")); }
+        records.push(RecordLoc { source: v[0], seq_offset: v[1], line_width: v[2], line_stride: v[3], masked: v[4] });
+    }
+    let mut bitmask = vec![0u8; seq_length];
+    r.read_exact(&mut bitmask).map_err(|_| bad("truncated"))?;
+
+    Ok(Pass1Cache {
+        include_gaps: flags & FLAG_GAPS != 0,
+        lowercase_as_missing: flags & FLAG_LOWERCASE != 0,
+        seq_length,
+        layout: SeqLayout { single_line: flags & FLAG_SINGLE_LINE != 0, fixed_width: flags & FLAG_FIXED_WIDTH != 0 },
+        sources, records, bitmask,
+    })
+}
+
+/// Index the records of a file to append (already mapped) to the cached alignment.
+///
+/// The new records must have the cached alignment length and IDs not in
+/// `existing`. The file itself is left as it is. Returns its records and layout.
+pub fn index_appended<'a>(
+    new: &'a [u8], new_name: &str, seq_length: usize, existing: &[FastaRecord],
+) -> io::Result<(Vec<FastaRecord<'a>>, SeqLayout)> {
+    let (recs, sl, layout) = index_fasta(new)?;
+    if sl != seq_length {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Sequences in '{}' have length {} but the alignment has {}.", new_name, sl, seq_length)));
+    }
+    let ids: HashSet<&[u8]> = existing.iter().map(|r| r.id).collect();
+    if let Some(dup) = recs.iter().find(|r| ids.contains(r.id)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Sequence '{}' in '{}' is already in the alignment.", String::from_utf8_lossy(dup.id), new_name)));
+    }
+    Ok((recs, layout))
+}
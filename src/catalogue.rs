@@ -0,0 +1,127 @@
+//! Resistance catalogue lookup.
+//!
+//! Genotypes every record at the positions of a mutation catalogue (position,
+//! ref, alt, drug, confidence) directly from the mmap, whether or not the site is
+//! variable in the current alignment, and summarises the calls per drug.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::extract::fetch_columns;
+use crate::fasta::FastaRecord;
+use crate::positions::ref_columns;
+use crate::types::{SeqLayout, IO_BUF};
+
+/// A catalogue mutation (single-base substitution).
+pub struct CatalogueEntry {
+    /// 0-based reference coordinate.
+    pub pos: usize,
+    pub ref_base: u8,
+    pub alt_base: u8,
+    pub drug: String,
+    pub confidence: String,
+    /// Alignment column of `pos`, set by `resolve_columns`.
+    pub column: usize,
+}
+
+/// Read a catalogue TSV with columns `position ref alt drug [confidence]`.
+///
+/// Positions are 1-based reference coordinates. A header line, `#` comments and
+/// entries that are not single-base substitutions are skipped; the number of
+/// skipped entries is returned.
+pub fn read_catalogue(path: &str) -> io::Result<(Vec<CatalogueEntry>, usize)> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open catalogue '{}': {}", path, e)))?;
+    let bad = |ln: usize, msg: &str| io::Error::new(io::ErrorKind::InvalidData,
+        format!("Catalogue '{}' line {}: {}", path, ln, msg));
+
+    let mut entries = Vec::new();
+    let mut skipped = 0;
+    for (li, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() { continue; }
+        let f: Vec<&str> = line.split('\t').map(str::trim).collect();
+        if f.len() < 4 { return Err(bad(li + 1, "expected at least 4 columns")); }
+        let pos: usize = match f[0].parse() {
+            Ok(p) => p,
+            Err(_) if li == 0 => continue,
+            Err(_) => return Err(bad(li + 1, "invalid position")),
+        };
+        if pos == 0 { return Err(bad(li + 1, "positions are 1-based")); }
+        if f[1].len() != 1 || f[2].len() != 1 { skipped += 1; continue; }
+        entries.push(CatalogueEntry {
+            pos: pos - 1,
+            ref_base: f[1].as_bytes()[0].to_ascii_uppercase(),
+            alt_base: f[2].as_bytes()[0].to_ascii_uppercase(),
+            drug: f[3].to_string(),
+            confidence: f.get(4).copied().unwrap_or(".").to_string(),
+            column: 0,
+        });
+    }
+    Ok((entries, skipped))
+}
+
+/// Map catalogue positions to alignment columns through the reference (first) sequence.
+///
+/// Returns the number of entries whose REF differs from the reference base.
+pub fn resolve_columns(entries: &mut [CatalogueEntry], ref_seq: &[u8]) -> io::Result<usize> {
+    let cols = ref_columns(ref_seq);
+    let mut mismatch = 0;
+    for e in entries.iter_mut() {
+        e.column = *cols.get(e.pos).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+            format!("Catalogue position {} lies beyond the reference length {}.", e.pos + 1, cols.len())))?;
+        if !ref_seq[e.column].eq_ignore_ascii_case(&e.ref_base) { mismatch += 1; }
+    }
+    Ok(mismatch)
+}
+
+/// Genotype all records at the catalogue columns and write a per-sample, per-drug report.
+///
+/// Columns: sample, drug, prediction (`R` if any catalogue ALT is present, `U` if
+/// none is present but some positions are missing, else `S`), detected mutations
+/// (`pos:ref>alt(confidence)`, `;`-separated) and missing positions (`,`-separated);
+/// gaps and IUPAC codes count as missing.
+pub fn write_resistance_report(
+    records: &[FastaRecord], layout: SeqLayout, upper: &[u8; 256], entries: &[CatalogueEntry], path: &str,
+) -> io::Result<()> {
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create resistance report '{}': {}", path, e)))?;
+    let mut w = BufWriter::with_capacity(IO_BUF, out);
+
+    // Unique sorted columns for sparse access; each entry points into them
+    let mut columns: Vec<usize> = entries.iter().map(|e| e.column).collect();
+    columns.sort_unstable();
+    columns.dedup();
+    let slot: Vec<usize> = entries.iter()
+        .map(|e| columns.binary_search(&e.column).unwrap_or(0)).collect();
+    let mut drugs: Vec<&str> = Vec::new();
+    for e in entries {
+        if !drugs.contains(&e.drug.as_str()) { drugs.push(&e.drug); }
+    }
+
+    writeln!(w, "sample\tdrug\tprediction\tmutations\tmissing")?;
+    let mut calls = vec![0u8; columns.len()];
+    for rec in records {
+        fetch_columns(rec, &columns, layout, upper, &mut calls);
+        for drug in &drugs {
+            let mut found = Vec::new();
+            let mut missing = Vec::new();
+            for (e, &s) in entries.iter().zip(slot.iter()).filter(|(e, _)| e.drug == *drug) {
+                let call = calls[s];
+                if call == e.alt_base {
+                    found.push(format!("{}:{}>{}({})", e.pos + 1, e.ref_base as char, e.alt_base as char, e.confidence));
+                } else if !matches!(call, b'A' | b'C' | b'G' | b'T') && !missing.contains(&(e.pos + 1)) {
+                    missing.push(e.pos + 1);
+                }
+            }
+            let prediction = if !found.is_empty() { "R" } else if !missing.is_empty() { "U" } else { "S" };
+            w.write_all(rec.id)?;
+            let missing: Vec<String> = missing.iter().map(|p| p.to_string()).collect();
+            writeln!(w, "\t{}\t{}\t{}\t{}", drug, prediction,
+                if found.is_empty() { ".".to_string() } else { found.join(";") },
+                if missing.is_empty() { ".".to_string() } else { missing.join(",") })?;
+        }
+    }
+
+    w.flush()
+}
@@ -0,0 +1,106 @@
+//! Haplotype collapsing for pass 2 output.
+//!
+//! Groups records whose variable-site strings are identical so that only one
+//! representative per haplotype is written to the output FASTA. Optionally,
+//! ambiguous bases (N, IUPAC, excluded gaps) are treated as compatible with any base.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::fasta::FastaRecord;
+
+/// A group of records sharing the same variable-site string.
+pub struct Haplotype {
+    /// Index of the record written to the output FASTA.
+    pub representative: usize,
+    /// Indices of all records in the group (including the representative), ascending.
+    pub members: Vec<usize>,
+    /// Variable-site string of the representative.
+    pub row: Vec<u8>,
+}
+
+/// Incremental haplotype index fed one row per record during pass 2.
+///
+/// Identical rows are merged on insertion via hashing. When `n_compatible` is set,
+/// `finish` additionally merges rows that differ only at ambiguous calls.
+pub struct HaplotypeIndex<'a> {
+    lookup: &'a [u8; 256],
+    n_compatible: bool,
+    exact: HashMap<Vec<u8>, usize>,
+    groups: Vec<Haplotype>,
+}
+
+impl<'a> HaplotypeIndex<'a> {
+    pub fn new(lookup: &'a [u8; 256], n_compatible: bool) -> Self {
+        HaplotypeIndex { lookup, n_compatible, exact: HashMap::new(), groups: Vec::new() }
+    }
+
+    /// Add record `si` with variable-site string `row`.
+    pub fn add(&mut self, si: usize, row: &[u8]) {
+        if let Some(&gi) = self.exact.get(row) {
+            self.groups[gi].members.push(si);
+            return;
+        }
+        self.exact.insert(row.to_vec(), self.groups.len());
+        self.groups.push(Haplotype { representative: si, members: vec![si], row: row.to_vec() });
+    }
+
+    /// Resolve final haplotypes, ordered by representative record index.
+    ///
+    /// In N-compatible mode, rows are visited from most to least complete and each
+    /// joins the first (most complete) haplotype it agrees with at every called site,
+    /// so a sparsely called record never becomes the representative of a richer one.
+    pub fn finish(self) -> Vec<Haplotype> {
+        let HaplotypeIndex { lookup, n_compatible, groups, .. } = self;
+        if !n_compatible { return groups; }
+
+        let missing = |row: &[u8]| row.iter().filter(|&&b| lookup[b as usize] == 0).count();
+        let mut order: Vec<(usize, Haplotype)> = groups.into_iter()
+            .map(|g| (missing(&g.row), g)).collect();
+        order.sort_by_key(|(m, g)| (*m, g.representative));
+
+        let mut merged: Vec<Haplotype> = Vec::new();
+        for (_, g) in order {
+            let target = merged.iter().position(|h| {
+                h.row.iter().zip(g.row.iter()).all(|(&a, &b)| {
+                    a == b || lookup[a as usize] == 0 || lookup[b as usize] == 0
+                })
+            });
+            match target {
+                Some(hi) => merged[hi].members.extend(g.members),
+                None => merged.push(g),
+            }
+        }
+
+        for h in merged.iter_mut() { h.members.sort_unstable(); }
+        merged.sort_by_key(|h| h.representative);
+        merged
+    }
+}
+
+/// Write haplotype → member mapping as TSV.
+///
+/// Columns: haplotype ID (`hap1`, `hap2`, … in output order), representative ID,
+/// member count, comma-separated member IDs.
+pub fn write_haplotype_map(
+    haplotypes: &[Haplotype], records: &[FastaRecord], path: &str,
+) -> io::Result<()> {
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create haplotype map '{}': {}", path, e)))?;
+    let mut w = BufWriter::new(out);
+
+    writeln!(w, "haplotype\trepresentative\tcount\tmembers")?;
+    for (hi, h) in haplotypes.iter().enumerate() {
+        write!(w, "hap{}\t", hi + 1)?;
+        w.write_all(records[h.representative].id)?;
+        write!(w, "\t{}\t", h.members.len())?;
+        for (mi, &m) in h.members.iter().enumerate() {
+            if mi > 0 { w.write_all(b",")?; }
+            w.write_all(records[m].id)?;
+        }
+        writeln!(w)?;
+    }
+
+    w.flush()
+}
@@ -0,0 +1,37 @@
+//! Inverse of extraction: rebuild full-length sequences from a reduced alignment.
+//!
+//! Every output sequence starts as a copy of the reference; the bases of the
+//! reduced alignment are then written back at the positions listed in the
+//! column position map.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::extract::write_record;
+use crate::fasta::{get_ref_seq, FastaRecord};
+use crate::types::{SeqLayout, IO_BUF};
+
+/// Expand each reduced record to full length and write the result as FASTA.
+///
+/// `targets[c]` is the 0-based position in `reference` that reduced column `c`
+/// is written to, or `None` to drop the column.
+pub fn expand_alignment(
+    records: &[FastaRecord], seq_length: usize, layout: SeqLayout,
+    reference: &[u8], targets: &[Option<usize>], output: &str,
+) -> io::Result<()> {
+    let out = File::create(output).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create output '{}': {}", output, e)))?;
+    let mut w = BufWriter::with_capacity(IO_BUF, out);
+    let mut full = reference.to_vec();
+
+    for rec in records {
+        let reduced = get_ref_seq(rec, seq_length, layout);
+        full.copy_from_slice(reference);
+        for (&b, t) in reduced.iter().zip(targets.iter()) {
+            if let Some(p) = *t { full[p] = b; }
+        }
+        write_record(&mut w, rec, &full)?;
+    }
+
+    w.flush()
+}
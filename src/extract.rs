@@ -1,35 +1,165 @@
 //! Pass 2: variable site extraction and output FASTA generation.
 //!
 //! Reads only the variable positions from each sequence (sparse access)
-//! and writes a reduced FASTA. Optionally collects a genotype matrix for VCF.
+//! and writes a reduced FASTA. Optionally collects a genotype matrix for VCF
+//! and collapses identical records into haplotypes.
 
 use std::fs::File;
 use std::io::{self, BufWriter, Write};
+use std::ops::Range;
 
+use rayon::prelude::*;
+
+use crate::dedup::{Haplotype, HaplotypeIndex};
 use crate::fasta::FastaRecord;
 use crate::types::*;
 
+/// Default size of the record rows fetched in parallel per round (64 MB).
+pub const ROUND_BYTES: usize = 64 * 1024 * 1024;
+
 /// Parameters for variable site extraction (pass 2).
 pub struct ExtractParams<'a> {
     pub records: &'a [FastaRecord<'a>],
     pub output: &'a str,
     pub collect_vcf: bool,
+    /// Collect per-site call counts (`ns`, `calls`) without the genotype matrix.
+    pub collect_counts: bool,
     pub lookup: &'a [u8; 256],
     pub upper: &'a [u8; 256],
     pub layout: SeqLayout,
+    /// Write one representative per haplotype instead of every record.
+    pub dedup: bool,
+    /// When deduplicating, treat ambiguous calls as compatible with any base.
+    pub n_compatible: bool,
+    /// Output column order as indices into the variable positions (default: as given).
+    pub column_order: Option<&'a [usize]>,
+    /// Bytes of record rows fetched per parallel round (at least one row).
+    pub round_bytes: usize,
+}
+
+impl<'a> ExtractParams<'a> {
+    /// Plain extraction: every record written, columns in order, nothing collected.
+    pub fn new(
+        records: &'a [FastaRecord<'a>], output: &'a str,
+        lookup: &'a [u8; 256], upper: &'a [u8; 256], layout: SeqLayout,
+    ) -> Self {
+        ExtractParams {
+            records, output, collect_vcf: false, collect_counts: false, lookup, upper, layout,
+            dedup: false, n_compatible: false, column_order: None, round_bytes: ROUND_BYTES,
+        }
+    }
+}
+
+/// Results of pass 2 beyond the written FASTA.
+pub struct Pass2Output {
+    /// Site-major genotype matrix (`num_var × num_samples`), if requested.
+    pub vcf_geno: Option<Vec<u8>>,
+    /// Haplotype groups, if deduplication was requested.
+    pub haplotypes: Option<Vec<Haplotype>>,
+}
+
+/// Write a single FASTA record (header with optional description, then sequence).
+pub fn write_record<W: Write>(w: &mut W, rec: &FastaRecord, seq: &[u8]) -> io::Result<()> {
+    w.write_all(b">")?;
+    w.write_all(rec.id)?;
+    if !rec.desc.is_empty() {
+        w.write_all(b" ")?;
+        w.write_all(rec.desc)?;
+    }
+    w.write_all(b"\n")?;
+    w.write_all(seq)?;
+    w.write_all(b"\n")
+}
+
+/// Read the bases at ascending alignment `columns` of one record into `out`, uppercased.
+///
+/// For single-line FASTA: direct byte access via `rec.data[seq_offset + pos]`.
+/// For fixed-width wrapping: direct access via `FastaRecord::offset_of`.
+/// For irregular wrapping: linear scan of the record, skipping newlines.
+pub fn fetch_columns(
+    rec: &FastaRecord, columns: &[usize], layout: SeqLayout, upper: &[u8; 256], out: &mut [u8],
+) {
+    let data = rec.data;
+    if layout.single_line {
+        let base = rec.seq_offset;
+        for (o, &p) in out.iter_mut().zip(columns.iter()) {
+            *o = upper[data[base + p] as usize];
+        }
+    } else if layout.fixed_width {
+        for (o, &p) in out.iter_mut().zip(columns.iter()) {
+            *o = upper[data[rec.offset_of(p)] as usize];
+        }
+    } else {
+        let mut pos = rec.seq_offset;
+        let end = data.len();
+        let mut base_idx = 0usize;
+        let mut col_idx = 0usize;
+        while col_idx < columns.len() && pos < end {
+            let b = data[pos];
+            pos += 1;
+            if b == b'\n' || b == b'\r' { continue; }
+            // `while` rather than `if`: the same column may be requested twice
+            while col_idx < columns.len() && base_idx == columns[col_idx] {
+                out[col_idx] = upper[b as usize];
+                col_idx += 1;
+            }
+            base_idx += 1;
+        }
+    }
+}
+
+/// Fetch `columns` of every record in rounds of at most `round_bytes` of rows
+/// (at least one row), each round in parallel over records. `each` gets the
+/// index of the round's first record and its rows (`columns.len()` bytes each), in order.
+pub fn fetch_rounds(
+    records: &[FastaRecord], columns: &[usize], layout: SeqLayout,
+    upper: &[u8; 256], round_bytes: usize, mut each: impl FnMut(usize, &[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let width = columns.len();
+    let round_rows = (round_bytes / width.max(1)).clamp(1, records.len().max(1));
+    let mut rows = vec![0u8; round_rows * width];
+    for start in (0..records.len()).step_by(round_rows) {
+        let end = (start + round_rows).min(records.len());
+        let round = &mut rows[..(end - start) * width];
+        round.par_chunks_mut(width.max(1)).zip(records[start..end].par_iter())
+            .for_each(|(row, rec)| fetch_columns(rec, columns, layout, upper, row));
+        each(start, round)?;
+    }
+    Ok(())
+}
+
+/// Write a record with its variable-site string permuted into output column order.
+fn write_ordered<W: Write>(
+    w: &mut W, rec: &FastaRecord, row: &[u8], order: Option<&[usize]>, buf: &mut [u8],
+) -> io::Result<()> {
+    match order {
+        Some(order) => {
+            for (b, &i) in buf.iter_mut().zip(order.iter()) { *b = row[i]; }
+            write_record(w, rec, buf)
+        }
+        None => write_record(w, rec, row),
+    }
 }
 
 /// Pass 2: extract variable sites from alignment and write output FASTA.
 ///
-/// For single-line FASTA: direct byte access via `data[seq_offset + pos]`.
-/// For multi-line: linear scan per record, skipping newlines.
+/// Each record is read sparsely at the variable positions via `fetch_columns`,
+/// in parallel over rounds of records (`fetch_rounds`); output order is preserved.
 ///
-/// Returns VCF genotype matrix if `collect_vcf` is true.
+/// Returns VCF genotype matrix if `collect_vcf` is true; per-site call counts are
+/// filled in with `collect_vcf` or `collect_counts`. With `dedup`, records are
+/// grouped by variable-site string and only representatives are written, in
+/// order of first appearance.
 pub fn pass2_extract(
-    data: &[u8], var_positions: &mut [VariablePosition], params: &ExtractParams<'_>,
-) -> io::Result<Option<Vec<u8>>> {
-    let ExtractParams { records, output, collect_vcf, lookup, upper, layout } = params;
+    var_positions: &mut [VariablePosition], params: &ExtractParams<'_>,
+) -> io::Result<Pass2Output> {
+    let ExtractParams {
+        records, output, collect_vcf, collect_counts, lookup, upper, layout, dedup, n_compatible, column_order,
+        round_bytes,
+    } = params;
+    let column_order = *column_order;
     let collect_vcf = *collect_vcf;
+    let collect_counts = collect_vcf || *collect_counts;
     let layout = *layout;
     let num_var = var_positions.len();
     let num_samples = records.len();
@@ -40,60 +170,113 @@ pub fn pass2_extract(
     let mut writer = BufWriter::with_capacity(IO_BUF, out_file);
 
     let mut vcf_geno: Vec<u8> = if collect_vcf { vec![0u8; num_var * num_samples] } else { Vec::new() };
-    let mut ns_counts: Vec<usize> = if collect_vcf { vec![0usize; num_var] } else { Vec::new() };
-    let mut var_buf = vec![0u8; num_var];
-
-    for (si, rec) in records.iter().enumerate() {
-        if layout.single_line {
-            let base = rec.seq_offset;
-            for (vi, &p) in pos_indices.iter().enumerate() {
-                var_buf[vi] = upper[data[base + p] as usize];
-            }
-        } else {
-            let mut pos = rec.seq_offset;
-            let end = data.len();
-            let mut base_idx = 0usize;
-            let mut var_idx = 0usize;
-            while var_idx < num_var && pos < end {
-                let b = data[pos];
-                pos += 1;
-                if b == b'\n' || b == b'\r' { continue; }
-                if base_idx == pos_indices[var_idx] {
-                    var_buf[var_idx] = upper[b as usize];
-                    var_idx += 1;
-                }
-                base_idx += 1;
+    let mut ns_counts: Vec<usize> = if collect_counts { vec![0usize; num_var] } else { Vec::new() };
+    let mut call_counts: Vec<CallCounts> = if collect_counts { vec![CallCounts::default(); num_var] } else { Vec::new() };
+    let mut out_buf = vec![0u8; if column_order.is_some() { num_var } else { 0 }];
+    let mut haps = if *dedup { Some(HaplotypeIndex::new(lookup, *n_compatible)) } else { None };
+
+    // Records are processed in rounds: the rows of a round are fetched in parallel,
+    // written (or grouped) in input order, then transposed into the genotype
+    // matrix and counted in parallel over sites.
+    fetch_rounds(records, &pos_indices, layout, upper, *round_bytes, |start, round| {
+        let n = round.len() / num_var.max(1);
+        for (i, rec) in records[start..start + n].iter().enumerate() {
+            let row = &round[i * num_var..(i + 1) * num_var];
+            match haps.as_mut() {
+                Some(h) => h.add(start + i, row),
+                None => write_ordered(&mut writer, rec, row, column_order, &mut out_buf)?,
             }
         }
 
-        writer.write_all(b">")?;
-        writer.write_all(rec.id)?;
-        if !rec.desc.is_empty() {
-            writer.write_all(b" ")?;
-            writer.write_all(rec.desc)?;
+        if collect_vcf {
+            vcf_geno.par_chunks_mut(num_samples.max(1)).enumerate().for_each(|(vi, site)| {
+                for i in 0..n { site[start + i] = round[i * num_var + vi]; }
+            });
         }
-        writer.write_all(b"\n")?;
-        writer.write_all(&var_buf)?;
-        writer.write_all(b"\n")?;
+        if collect_counts {
+            ns_counts.par_iter_mut().zip(call_counts.par_iter_mut()).enumerate()
+                .for_each(|(vi, (ns, calls))| {
+                    for i in 0..n {
+                        let nuc = round[i * num_var + vi];
+                        if lookup[nuc as usize] != 0 { *ns += 1; }
+                        calls.add(nuc);
+                    }
+                });
+        }
+        Ok(())
+    })?;
 
-        if collect_vcf {
-            for (vi, &nuc) in var_buf.iter().enumerate() {
-                vcf_geno[vi * num_samples + si] = nuc;
-                if lookup[nuc as usize] != 0 {
-                    ns_counts[vi] += 1;
-                }
-            }
+    let haplotypes = haps.map(HaplotypeIndex::finish);
+    if let Some(ref hs) = haplotypes {
+        for h in hs {
+            write_ordered(&mut writer, &records[h.representative], &h.row, column_order, &mut out_buf)?;
         }
     }
 
     writer.flush()?;
 
-    if collect_vcf {
+    if collect_counts {
         for (vi, vp) in var_positions.iter_mut().enumerate() {
             vp.ns = ns_counts[vi];
+            vp.calls = call_counts[vi];
+        }
+    }
+
+    match haplotypes {
+        Some(ref hs) => eprintln!("[snpick] Pass 2: Wrote {} haplotypes ({} sequences) to {}.",
+            hs.len(), num_samples, output),
+        None => eprintln!("[snpick] Pass 2: Wrote {} sequences to {}.", num_samples, output),
+    }
+    Ok(Pass2Output {
+        vcf_geno: if collect_vcf { Some(vcf_geno) } else { None },
+        haplotypes,
+    })
+}
+
+/// Split the variable positions into consecutive blocks whose genotype matrices
+/// (`block_len × num_samples` bytes) fit in `max_bytes`.
+///
+/// Blocks never end between columns `e - 1` and `e` for which `joined(e)` holds
+/// (co-varying MNP sites); a joined run that does not fit the budget is an error,
+/// as is an error from `joined`.
+pub fn geno_blocks(
+    var_positions: &[VariablePosition], num_samples: usize, max_bytes: usize,
+    mut joined: impl FnMut(usize) -> io::Result<bool>,
+) -> io::Result<Vec<Range<usize>>> {
+    let n = var_positions.len();
+    let per_block = (max_bytes / num_samples.max(1)).max(1);
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    while start < n {
+        let mut end = (start + per_block).min(n);
+        while end < n && end > start && joined(end)? { end -= 1; }
+        if end == start {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "More than {} co-varying adjacent sites from column {} do not fit the VCF genotype budget; \
+                raise --max-memory or drop --merge-mnps.", per_block, var_positions[start].index + 1)));
         }
+        blocks.push(start..end);
+        start = end;
     }
+    Ok(blocks)
+}
 
-    eprintln!("[snpick] Pass 2: Wrote {} sequences to {}.", num_samples, output);
-    if collect_vcf { Ok(Some(vcf_geno)) } else { Ok(None) }
+/// Fill `out` with the site-major genotype matrix of `columns`
+/// (`out[ci * records.len() + si]`), re-reading every record from the mmap in
+/// parallel rounds of at most `round_bytes` (see `fetch_rounds`).
+pub fn fetch_block(
+    records: &[FastaRecord], columns: &[usize], layout: SeqLayout,
+    upper: &[u8; 256], round_bytes: usize, out: &mut Vec<u8>,
+) -> io::Result<()> {
+    let num_samples = records.len();
+    let width = columns.len();
+    out.clear();
+    out.resize(width * num_samples, 0);
+    fetch_rounds(records, columns, layout, upper, round_bytes, |start, round| {
+        let n = round.len() / width.max(1);
+        out.par_chunks_mut(num_samples.max(1)).enumerate().for_each(|(ci, site)| {
+            for i in 0..n { site[start + i] = round[i * width + ci]; }
+        });
+        Ok(())
+    })
 }
@@ -1,24 +1,57 @@
 //! Zero-copy FASTA parser over memory-mapped data.
 //!
 //! Records are indexed by scanning for `>` headers and tracking sequence offsets.
-//! No data is copied — IDs and descriptions are `&[u8]` slices into the mmap.
+//! No data is copied — IDs and descriptions are `&[u8]` slices into the mmap, and
+//! each record keeps the mapped file it lies in, so records may come from several files.
 
-use std::io;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 
 use crate::types::{SeqLayout, MAX_SEQ_LENGTH};
 
 /// A FASTA record as zero-copy slices into memory-mapped data.
 pub struct FastaRecord<'a> {
+    /// The mapped file holding the record; offsets are relative to it.
+    pub data: &'a [u8],
     pub id: &'a [u8],
     pub desc: &'a [u8],
     pub seq_offset: usize,
+    /// Bases per line, or 0 if the record is irregularly wrapped.
+    pub line_width: usize,
+    /// Bytes per line including the line break.
+    pub line_stride: usize,
+}
+
+impl<'a> FastaRecord<'a> {
+    /// Byte offset of base `pos` in a fixed-width record.
+    #[inline]
+    pub fn offset_of(&self, pos: usize) -> usize {
+        self.seq_offset + pos / self.line_width * self.line_stride + pos % self.line_width
+    }
+
+    /// The sequence lines of a fixed-width record, without line breaks.
+    pub fn lines(&self, seq_length: usize) -> impl Iterator<Item = &'a [u8]> {
+        let (data, off, width, stride) = (self.data, self.seq_offset, self.line_width, self.line_stride);
+        (0..seq_length.div_ceil(width)).map(move |k| {
+            let start = off + k * stride;
+            &data[start..start + width.min(seq_length - k * width)]
+        })
+    }
 }
 
 /// Index FASTA records from memory-mapped data. Zero-copy: stores `&[u8]` slices.
 ///
 /// Returns `(records, seq_length, layout)`.
 /// All sequences must have the same length (alignment requirement).
+/// Records wrapped at a fixed width (every line but the last of the same length,
+/// consistent line breaks, no blank lines) get `line_width`/`line_stride` for O(1) access.
 pub fn index_fasta(data: &[u8]) -> io::Result<(Vec<FastaRecord<'_>>, usize, SeqLayout)> {
+    index_records(data, false)
+}
+
+/// As `index_fasta`, but with `allow_empty` zero-length sequences are accepted
+/// (the reduced alignment of an input without variable sites).
+pub fn index_records(data: &[u8], allow_empty: bool) -> io::Result<(Vec<FastaRecord<'_>>, usize, SeqLayout)> {
     if data.is_empty() {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "Input FASTA is empty (0 bytes)."));
     }
@@ -28,6 +61,7 @@ pub fn index_fasta(data: &[u8]) -> io::Result<(Vec<FastaRecord<'_>>, usize, SeqL
     let len = data.len();
     let mut seq_length = 0usize;
     let mut is_single_line = true;
+    let mut is_fixed_width = true;
 
     while pos < len {
         while pos < len && (data[pos] == b'\n' || data[pos] == b'\r') { pos += 1; }
@@ -54,25 +88,38 @@ pub fn index_fasta(data: &[u8]) -> io::Result<(Vec<FastaRecord<'_>>, usize, SeqL
 
         let seq_offset = pos;
 
-        // Count bases
+        // Count bases, checking for fixed-width wrapping
         let mut seq_len = 0usize;
         let mut line_count = 0usize;
+        let (mut width, mut stride) = (0usize, 0usize);
+        let (mut prev_len, mut prev_bytes) = (0usize, 0usize);
+        let (mut regular, mut blank) = (true, false);
         while pos < len && data[pos] != b'>' {
             let line_start = pos;
             while pos < len && data[pos] != b'\n' && data[pos] != b'\r' { pos += 1; }
             let line_len = pos - line_start;
+            if pos < len && data[pos] == b'\r' { pos += 1; }
+            if pos < len && data[pos] == b'\n' { pos += 1; }
             if line_len > 0 {
+                if line_count == 0 {
+                    (width, stride) = (line_len, pos - line_start);
+                } else if blank || prev_len != width || prev_bytes != stride || line_len > width {
+                    // Every line but the last must hold `width` bases and the same line break
+                    regular = false;
+                }
+                (prev_len, prev_bytes) = (line_len, pos - line_start);
                 seq_len += line_len;
                 line_count += 1;
+            } else if line_count > 0 {
+                blank = true;
             }
-            if pos < len && data[pos] == b'\r' { pos += 1; }
-            if pos < len && data[pos] == b'\n' { pos += 1; }
         }
 
         if line_count > 1 { is_single_line = false; }
+        if !regular { is_fixed_width = false; }
 
         if records.is_empty() {
-            if seq_len == 0 {
+            if seq_len == 0 && !allow_empty {
                 return Err(io::Error::new(io::ErrorKind::InvalidData, "First sequence has length 0."));
             }
             if seq_len > MAX_SEQ_LENGTH {
@@ -87,20 +134,133 @@ pub fn index_fasta(data: &[u8]) -> io::Result<(Vec<FastaRecord<'_>>, usize, SeqL
                     id_str, records.len() + 1, seq_len, seq_length)));
         }
 
-        records.push(FastaRecord { id, desc, seq_offset });
+        let line_width = if regular { width } else { 0 };
+        records.push(FastaRecord { data, id, desc, seq_offset, line_width, line_stride: stride });
     }
 
     if records.is_empty() {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "Input FASTA is empty."));
     }
 
-    Ok((records, seq_length, SeqLayout { single_line: is_single_line }))
+    Ok((records, seq_length, SeqLayout { single_line: is_single_line, fixed_width: is_fixed_width }))
+}
+
+/// Byte offset of the `>` opening the header line that ends just before sequence offset `offset`.
+fn header_start(data: &[u8], offset: usize) -> Option<usize> {
+    let mut end = offset.min(data.len());
+    while end > 0 && (data[end - 1] == b'\n' || data[end - 1] == b'\r') { end -= 1; }
+    let start = data[..end].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    (data.get(start) == Some(&b'>')).then_some(start)
+}
+
+/// ID and description of the header line ending just before sequence offset `offset`.
+pub fn header_before(data: &[u8], offset: usize) -> Option<(&[u8], &[u8])> {
+    let start = header_start(data, offset)?;
+    let end = start + data[start..].iter().position(|&b| b == b'\n' || b == b'\r').unwrap_or(data.len() - start);
+    let header = &data[start + 1..end];
+    Some(match header.iter().position(|&b| b == b' ' || b == b'\t') {
+        Some(sp) => (&header[..sp], &header[sp + 1..]),
+        None => (header, &data[0..0]),
+    })
+}
+
+/// Build the record index from a samtools-style `.fai` instead of scanning.
+///
+/// Each line holds `name length offset linebases linebytes`. The index alone must
+/// describe an alignment (equal lengths); each record's header is located just
+/// before its offset, and its name and extent are checked against `data`. The
+/// records must cover the whole FASTA: each header follows the previous record's
+/// last line, and only line breaks may follow the last record.
+pub fn read_fai<'a>(data: &'a [u8], path: &str) -> io::Result<(Vec<FastaRecord<'a>>, usize, SeqLayout)> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open index '{}': {}", path, e)))?;
+    let bad = |ln: usize, msg: String| io::Error::new(io::ErrorKind::InvalidData,
+        format!("Index '{}' line {}: {}", path, ln, msg));
+
+    let mut records = Vec::new();
+    let mut seq_length = 0usize;
+    let mut single_line = true;
+    let only_breaks = |bytes: &[u8]| bytes.iter().all(|&b| b == b'\n' || b == b'\r');
+    // Where the previous record's sequence ends
+    let mut end = 0;
+    for (li, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() { continue; }
+        let f: Vec<&str> = line.split('\t').collect();
+        if f.len() < 5 { return Err(bad(li + 1, "expected 5 columns".into())); }
+        let num = |i: usize| f[i].trim().parse::<usize>()
+            .map_err(|_| bad(li + 1, format!("invalid number '{}'", f[i])));
+        let (length, offset, width, stride) = (num(1)?, num(2)?, num(3)?, num(4)?);
+        if length == 0 || width == 0 || stride < width {
+            return Err(bad(li + 1, "zero length or inconsistent line widths".into()));
+        }
+        if records.is_empty() {
+            if length > MAX_SEQ_LENGTH {
+                return Err(bad(li + 1, format!("sequence length {} exceeds maximum", length)));
+            }
+            seq_length = length;
+        } else if length != seq_length {
+            return Err(bad(li + 1, format!("sequence '{}' (#{}) has length {} but expected {}",
+                f[0], records.len() + 1, length, seq_length)));
+        }
+
+        let (id, desc) = header_before(data, offset)
+            .ok_or_else(|| bad(li + 1, format!("no header before offset {}", offset)))?;
+        match header_start(data, offset) {
+            Some(h) if h >= end && only_breaks(&data[end..h]) => {}
+            _ => return Err(bad(li + 1, format!(
+                "record '{}' does not directly follow the previous record in the FASTA; the index is stale", f[0]))),
+        }
+        if id != f[0].as_bytes() {
+            return Err(bad(li + 1, format!("record '{}' does not match header '{}' in the FASTA; the index is stale",
+                f[0], String::from_utf8_lossy(id))));
+        }
+        let rec = FastaRecord { data, id, desc, seq_offset: offset, line_width: width, line_stride: stride };
+        // Spot checks: the first line break and the end of the sequence are where the index puts them
+        let last = rec.offset_of(length - 1);
+        let is_break = |i: usize| i >= data.len() || matches!(data[i], b'\n' | b'\r');
+        if last >= data.len() || is_break(last) || data[last] == b'>' || !is_break(last + 1)
+            || (length > width && (is_break(offset + width - 1) || !is_break(offset + width))) {
+            return Err(bad(li + 1, format!("record '{}' does not fit the FASTA; the index is stale", f[0])));
+        }
+        if length > width { single_line = false; }
+        end = last + 1;
+        records.push(rec);
+    }
+
+    if records.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Index '{}' is empty.", path)));
+    }
+    if !only_breaks(&data[end..]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "Index '{}' does not cover the whole FASTA (data after byte {}); the index is stale.", path, end)));
+    }
+    Ok((records, seq_length, SeqLayout { single_line, fixed_width: true }))
+}
+
+/// Write a samtools-style `.fai` for fixed-width records.
+pub fn write_fai(records: &[FastaRecord], seq_length: usize, layout: SeqLayout, path: &str) -> io::Result<()> {
+    if !layout.fixed_width {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Cannot write a .fai index: sequences are not wrapped at a fixed line width."));
+    }
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create index '{}': {}", path, e)))?;
+    let mut w = BufWriter::new(out);
+    for rec in records {
+        w.write_all(rec.id)?;
+        writeln!(w, "\t{}\t{}\t{}\t{}", seq_length, rec.seq_offset, rec.line_width, rec.line_stride)?;
+    }
+    w.flush()
 }
 
 /// Extract reference sequence from first record.
-pub fn get_ref_seq(data: &[u8], rec: &FastaRecord, seq_length: usize, layout: SeqLayout) -> Vec<u8> {
+pub fn get_ref_seq(rec: &FastaRecord, seq_length: usize, layout: SeqLayout) -> Vec<u8> {
+    let data = rec.data;
     if layout.single_line {
         data[rec.seq_offset..rec.seq_offset + seq_length].to_vec()
+    } else if layout.fixed_width {
+        rec.lines(seq_length).flatten().copied().collect()
     } else {
         let mut seq = Vec::with_capacity(seq_length);
         let mut pos = rec.seq_offset;
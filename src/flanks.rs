@@ -0,0 +1,78 @@
+//! Flanking reference context around variable sites.
+//!
+//! Writes one FASTA entry per variable position with up to `k` ungapped
+//! reference bases on each side. The site itself is written as `[REF/ALT,...]`;
+//! other variable sites inside the flanks are written as IUPAC codes of their
+//! alleles, so that probe and primer design can avoid them.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::types::*;
+
+/// IUPAC code of all (non-gap) alleles of a variable position.
+fn site_iupac(vp: &VariablePosition, lookup: &[u8; 256]) -> u8 {
+    let bits = std::iter::once(&vp.ref_base).chain(vp.alt_bases.iter())
+        .fold(0u8, |acc, &b| acc | lookup[b as usize]);
+    bits_to_iupac(bits)
+}
+
+/// Write flanking sequences for every variable position.
+///
+/// Headers are `>site<n> position=<col> ref_position=<pos> nearby=<count>`, where
+/// `nearby` counts other variable sites within the flanks. Columns where the
+/// reference has a gap are skipped when collecting context, and forced panel
+/// sites without an ALT allele are written as plain reference context.
+pub fn write_flanks(
+    var_positions: &[VariablePosition], ref_seq: &[u8], k: usize,
+    lookup: &[u8; 256], path: &str,
+) -> io::Result<usize> {
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create flank file '{}': {}", path, e)))?;
+    let mut w = BufWriter::with_capacity(IO_BUF, out);
+
+    let var_at = |col: usize| var_positions.binary_search_by_key(&col, |v| v.index).ok()
+        .filter(|&vi| !var_positions[vi].alt_bases.is_empty());
+    let base_at = |col: usize| match var_at(col) {
+        Some(vi) => site_iupac(&var_positions[vi], lookup),
+        None => ref_seq[col].to_ascii_uppercase(),
+    };
+
+    let mut with_nearby = 0;
+    let mut left = Vec::with_capacity(k);
+    let mut right = Vec::with_capacity(k);
+    for (vi, vp) in var_positions.iter().enumerate() {
+        left.clear();
+        right.clear();
+        let mut nearby = 0;
+        let mut col = vp.index;
+        while left.len() < k && col > 0 {
+            col -= 1;
+            if is_gap(ref_seq[col]) { continue; }
+            if var_at(col).is_some() { nearby += 1; }
+            left.push(base_at(col));
+        }
+        left.reverse();
+        let mut col = vp.index + 1;
+        while right.len() < k && col < ref_seq.len() {
+            if !is_gap(ref_seq[col]) {
+                if var_at(col).is_some() { nearby += 1; }
+                right.push(base_at(col));
+            }
+            col += 1;
+        }
+        if nearby > 0 { with_nearby += 1; }
+
+        let rp = vp.ref_pos.map_or(".".to_string(), |p| (p + 1).to_string());
+        writeln!(w, ">site{} position={} ref_position={} nearby={}", vi + 1, vp.index + 1, rp, nearby)?;
+        w.write_all(&left)?;
+        write!(w, "[{}", vp.ref_base as char)?;
+        for &ab in &vp.alt_bases { write!(w, "/{}", ab as char)?; }
+        write!(w, "]")?;
+        w.write_all(&right)?;
+        writeln!(w)?;
+    }
+
+    w.flush()?;
+    Ok(with_nearby)
+}
@@ -0,0 +1,213 @@
+//! VCF → variable-site alignment conversion.
+//!
+//! Reads haploid (or homozygous diploid) genotype calls from one or more VCFs and
+//! rebuilds the bitmask that the equivalent full alignment (reference with each
+//! sample's alleles substituted) would produce in pass 1, so that site
+//! classification and ASC counts come from the same `analyze` step.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::extract::write_record;
+use crate::fasta::FastaRecord;
+use crate::types::{build_scan_table, VariablePosition, IO_BUF};
+
+/// Per-sample calls that differ from the implicit reference allele.
+#[derive(Default)]
+pub struct VcfCalls {
+    pub samples: Vec<String>,
+    /// Per sample: `(0-based position, base)`, sorted by position after `finish`.
+    pub calls: Vec<Vec<(usize, u8)>>,
+    /// Records skipped because an allele is not a substitution (indels, symbolic).
+    pub skipped: usize,
+    /// Records whose REF disagrees with the reference sequence.
+    pub ref_mismatch: usize,
+    /// Sample positions given different non-reference alleles by two records (set to N).
+    pub conflicts: usize,
+    chrom: Option<String>,
+    /// Per VCF: positions covered by more than one record, with their record count.
+    repeats: Vec<HashMap<usize, usize>>,
+    /// Per sample: the VCF it was read from.
+    sample_file: Vec<usize>,
+    /// Sample names seen so far, for the duplicate check.
+    names: HashSet<String>,
+}
+
+impl VcfCalls {
+    /// Sort each sample's calls by position and resolve positions covered by
+    /// more than one record (e.g. split multi-allelic rows, which code the other
+    /// ALTs as reference): a non-reference call beats a reference call, any call
+    /// beats a missing one, and two different non-reference calls give N.
+    pub fn finish(&mut self) {
+        for (si, c) in self.calls.iter_mut().enumerate() {
+            let repeats = &self.repeats[self.sample_file[si]];
+            c.sort_by_key(|&(p, _)| p);
+            let mut keep: Vec<(usize, u8)> = Vec::with_capacity(c.len());
+            for group in c.chunk_by(|a, b| a.0 == b.0) {
+                let p = group[0].0;
+                let mut called = group.iter().map(|&(_, b)| b).filter(|&b| b != b'N');
+                match called.next() {
+                    Some(b) if called.all(|o| o == b) => keep.push((p, b)),
+                    Some(_) => {
+                        self.conflicts += 1;
+                        keep.push((p, b'N'));
+                    }
+                    // Only missing calls here; another record covering p called the reference
+                    None if group.len() < repeats.get(&p).copied().unwrap_or(1) => {}
+                    None => keep.push((p, b'N')),
+                }
+            }
+            *c = keep;
+        }
+    }
+}
+
+/// Decode a GT field to an allele index. Heterozygous and missing calls → `None`.
+fn parse_gt(gt: &str) -> Option<usize> {
+    let mut alleles = gt.split(['/', '|']);
+    let first = alleles.next()?;
+    if first == "." { return None; }
+    if alleles.any(|a| a != first) { return None; }
+    first.parse().ok()
+}
+
+/// Read one VCF and append its samples and calls to `calls`.
+///
+/// Only single-contig VCFs are supported (all files must share one CHROM).
+/// Substitutions of any length (SNPs, MNPs with equal-length REF/ALT) are applied
+/// base by base; `*` alleles become gaps; other records are skipped.
+pub fn read_vcf_calls(path: &str, reference: &[u8], calls: &mut VcfCalls) -> io::Result<()> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open VCF '{}': {}", path, e)))?;
+    let bad = |ln: usize, msg: String| io::Error::new(io::ErrorKind::InvalidData,
+        format!("VCF '{}' line {}: {}", path, ln, msg));
+
+    let mut first_sample = None;
+    let mut cover: HashMap<usize, usize> = HashMap::new();
+    for (li, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let ln = li + 1;
+        if line.starts_with("##") || line.trim().is_empty() { continue; }
+        let f: Vec<&str> = line.split('\t').collect();
+        if line.starts_with('#') {
+            first_sample = Some(calls.samples.len());
+            for name in f.iter().skip(9) {
+                if !calls.names.insert(name.to_string()) {
+                    return Err(bad(ln, format!("duplicate sample '{}'", name)));
+                }
+                calls.samples.push(name.to_string());
+                calls.calls.push(Vec::new());
+                calls.sample_file.push(calls.repeats.len());
+            }
+            continue;
+        }
+        let s0 = first_sample.ok_or_else(|| bad(ln, "record before #CHROM header".into()))?;
+        if f.len() < 10 { return Err(bad(ln, "expected FORMAT and sample columns".into())); }
+
+        match calls.chrom {
+            Some(ref c) if c != f[0] => return Err(bad(ln,
+                format!("multiple contigs ('{}', '{}') are not supported", c, f[0]))),
+            Some(_) => {}
+            None => calls.chrom = Some(f[0].to_string()),
+        }
+
+        let pos: usize = f[1].parse().map_err(|_| bad(ln, format!("invalid POS '{}'", f[1])))?;
+        let ref_allele = f[3].as_bytes();
+        if pos == 0 || pos - 1 + ref_allele.len() > reference.len() {
+            return Err(bad(ln, format!("POS {} outside reference of length {}", pos, reference.len())));
+        }
+        let p0 = pos - 1;
+
+        // Alleles as equal-length base strings; anything else is skipped
+        let mut alleles: Vec<Vec<u8>> = vec![ref_allele.to_ascii_uppercase()];
+        let mut substitution = true;
+        for a in f[4].split(',') {
+            let a = a.as_bytes();
+            if a == b"*" {
+                alleles.push(vec![b'-'; ref_allele.len()]);
+            } else if a == b"." {
+                alleles.push(ref_allele.to_ascii_uppercase());
+            } else if a.len() == ref_allele.len() && a.iter().all(u8::is_ascii_alphabetic) {
+                alleles.push(a.to_ascii_uppercase());
+            } else {
+                substitution = false;
+            }
+        }
+        if !substitution { calls.skipped += 1; continue; }
+        for k in 0..ref_allele.len() { *cover.entry(p0 + k).or_insert(0) += 1; }
+        if !reference[p0..p0 + ref_allele.len()].eq_ignore_ascii_case(ref_allele) {
+            calls.ref_mismatch += 1;
+        }
+
+        let gt_idx = f[8].split(':').position(|k| k == "GT")
+            .ok_or_else(|| bad(ln, "FORMAT has no GT".into()))?;
+        for (si, sample) in f[9..].iter().enumerate() {
+            let dest = &mut calls.calls[s0 + si];
+            let gt = sample.split(':').nth(gt_idx).unwrap_or(".");
+            match parse_gt(gt) {
+                Some(ai) if ai < alleles.len() => {
+                    for (k, &b) in alleles[ai].iter().enumerate() {
+                        if !b.eq_ignore_ascii_case(&reference[p0 + k]) {
+                            dest.push((p0 + k, b));
+                        }
+                    }
+                }
+                Some(ai) => return Err(bad(ln, format!("allele index {} out of range", ai))),
+                None => for k in 0..ref_allele.len() { dest.push((p0 + k, b'N')); },
+            }
+        }
+    }
+    cover.retain(|_, n| *n > 1);
+    calls.repeats.push(cover);
+    Ok(())
+}
+
+/// Build the pass 1 bitmask of the equivalent full alignment.
+///
+/// A position contributes the reference base unless every sample overrides it.
+pub fn calls_bitmask(calls: &VcfCalls, reference: &[u8], lookup: &[u8; 256]) -> Vec<u8> {
+    let num_samples = calls.samples.len();
+    let table = build_scan_table(lookup);
+    let lookup = &table;
+    let mut overridden: HashMap<usize, usize> = HashMap::new();
+    let mut bitmask = vec![0u8; reference.len()];
+    for c in &calls.calls {
+        for &(p, b) in c {
+            *overridden.entry(p).or_insert(0) += 1;
+            bitmask[p] |= lookup[b as usize];
+        }
+    }
+    if num_samples > 0 {
+        for (p, (bm, &r)) in bitmask.iter_mut().zip(reference.iter()).enumerate() {
+            if overridden.get(&p).copied().unwrap_or(0) < num_samples {
+                *bm |= lookup[r as usize];
+            }
+        }
+    }
+    bitmask
+}
+
+/// Write the variable-site alignment for all VCF samples.
+pub fn write_calls_fasta(
+    calls: &VcfCalls, reference: &[u8], var_positions: &[VariablePosition],
+    upper: &[u8; 256], output: &str,
+) -> io::Result<()> {
+    let out = File::create(output).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create output '{}': {}", output, e)))?;
+    let mut w = BufWriter::with_capacity(IO_BUF, out);
+    let mut row = vec![0u8; var_positions.len()];
+
+    for (name, c) in calls.samples.iter().zip(calls.calls.iter()) {
+        // Merge-walk sorted calls against sorted variable positions
+        let mut ci = 0;
+        for (b, vp) in row.iter_mut().zip(var_positions.iter()) {
+            while ci < c.len() && c[ci].0 < vp.index { ci += 1; }
+            *b = if ci < c.len() && c[ci].0 == vp.index { c[ci].1 } else { upper[reference[vp.index] as usize] };
+        }
+        let rec = FastaRecord { data: &[], id: name.as_bytes(), desc: &[], seq_offset: 0, line_width: 0, line_stride: 0 };
+        write_record(&mut w, &rec, &row)?;
+    }
+
+    w.flush()
+}
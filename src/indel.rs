@@ -0,0 +1,175 @@
+//! Indel calling from gap runs.
+//!
+//! With `-g`, gaps are alleles at single columns. This module turns each
+//! sample's contiguous runs of gaps against reference bases (deletions), or of
+//! bases against reference gaps (insertions), into left-aligned VCF indel
+//! records anchored on the preceding reference base. Runs are contiguous across
+//! columns where both the sample and the reference have a gap.
+
+use std::collections::HashMap;
+
+use crate::positions::ref_columns;
+use crate::types::{is_gap, VariablePosition};
+
+/// A biallelic indel record.
+pub struct IndelRecord {
+    /// Alignment column of the anchor base.
+    pub column: usize,
+    pub ref_allele: Vec<u8>,
+    pub alt_allele: Vec<u8>,
+    /// Per sample: `Some(0)` reference, `Some(1)` indel, `None` missing or overlapping event.
+    pub genotypes: Vec<Option<u8>>,
+}
+
+/// Indel records plus the numbers of events that were skipped.
+pub struct IndelCalls {
+    pub records: Vec<IndelRecord>,
+    /// Events that could not be anchored (at the very start of the reference).
+    pub unanchored: usize,
+    /// Deletions whose REF allele spans a gap column of the reference, so that
+    /// it could not be placed at consecutive alignment columns.
+    pub ref_gap: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind { Del, Ins }
+
+/// An event being extended along one sample.
+struct Run {
+    kind: Kind,
+    first_vi: usize,
+    last_vi: usize,
+    /// Deletions: first and last deleted reference coordinate.
+    del: (usize, usize),
+    /// Insertions: number of reference bases before the inserted bases, and the bases.
+    ins_at: usize,
+    ins: Vec<u8>,
+}
+
+/// Normalized event: anchor reference coordinate, REF and ALT alleles.
+type EventKey = (usize, Vec<u8>, Vec<u8>);
+
+/// Left-align an event against the ungapped reference and build `(anchor, REF, ALT)`.
+fn normalize(run: &Run, reference: &[u8]) -> Option<EventKey> {
+    match run.kind {
+        Kind::Del => {
+            let (mut s, mut e) = run.del;
+            while s > 0 && reference[s - 1] == reference[e] { s -= 1; e -= 1; }
+            if s == 0 { return None; }
+            Some((s - 1, reference[s - 1..=e].to_vec(), vec![reference[s - 1]]))
+        }
+        Kind::Ins => {
+            if run.ins_at == 0 { return None; }
+            let mut a = run.ins_at - 1;
+            let mut ins = run.ins.clone();
+            while a > 0 && reference[a] == *ins.last().unwrap() {
+                ins.rotate_right(1);
+                ins[0] = reference[a];
+                a -= 1;
+            }
+            let mut alt = vec![reference[a]];
+            alt.extend_from_slice(&ins);
+            Some((a, vec![reference[a]], alt))
+        }
+    }
+}
+
+/// Call indels from the site-major genotype matrix (`geno[vi * num_samples + si]`).
+///
+/// `ref_seq` is the aligned reference (first) sequence. Samples that do not carry
+/// an event get `0` if they match the reference gap pattern over the event's
+/// columns, and missing otherwise (N, or an overlapping indel).
+pub fn call_indels(
+    geno: &[u8], num_samples: usize, var_positions: &[VariablePosition], ref_seq: &[u8],
+) -> IndelCalls {
+    let reference: Vec<u8> = ref_seq.iter().filter(|&&b| !is_gap(b))
+        .map(|b| b.to_ascii_uppercase()).collect();
+    let cols = ref_columns(ref_seq);
+
+    // Reference bases in columns before each variable position
+    let mut ref_before = Vec::with_capacity(var_positions.len());
+    let (mut count, mut col) = (0usize, 0usize);
+    for vp in var_positions {
+        while col < vp.index {
+            if !is_gap(ref_seq[col]) { count += 1; }
+            col += 1;
+        }
+        ref_before.push(count);
+    }
+
+    // Event → carriers and the span of variable positions it covers
+    let mut events: HashMap<EventKey, (Vec<usize>, usize, usize)> = HashMap::new();
+    let (mut unanchored, mut across_gap) = (0, 0);
+    for si in 0..num_samples {
+        let mut run: Option<Run> = None;
+        let mut flush = |run: &mut Option<Run>| {
+            if let Some(r) = run.take() {
+                match normalize(&r, &reference) {
+                    // POS is an alignment column: REF must lie on consecutive columns
+                    Some((a, ref ra, _)) if cols[a + ra.len() - 1] - cols[a] != ra.len() - 1 => across_gap += 1,
+                    Some(key) => {
+                        let e = events.entry(key).or_insert((Vec::new(), r.first_vi, r.last_vi));
+                        e.0.push(si);
+                        e.1 = e.1.min(r.first_vi);
+                        e.2 = e.2.max(r.last_vi);
+                    }
+                    None => unanchored += 1,
+                }
+            }
+        };
+        let mut prev: Option<usize> = None;
+        for (vi, vp) in var_positions.iter().enumerate() {
+            // Contiguous when no reference base lies between the previous column and this one
+            let contiguous = prev.is_some_and(|p| ref_before[vi]
+                == ref_before[p] + usize::from(var_positions[p].ref_pos.is_some()));
+            if !contiguous { flush(&mut run); }
+            prev = Some(vi);
+
+            let call = geno[vi * num_samples + si];
+            let ref_gap = vp.ref_pos.is_none();
+            match (is_gap(call), ref_gap) {
+                (true, true) => {}
+                (true, false) => {
+                    let rp = vp.ref_pos.unwrap_or(0);
+                    match run {
+                        Some(ref mut r) if r.kind == Kind::Del => { r.del.1 = rp; r.last_vi = vi; }
+                        _ => {
+                            flush(&mut run);
+                            run = Some(Run { kind: Kind::Del, first_vi: vi, last_vi: vi,
+                                del: (rp, rp), ins_at: 0, ins: Vec::new() });
+                        }
+                    }
+                }
+                (false, true) if matches!(call, b'A' | b'C' | b'G' | b'T') => {
+                    match run {
+                        Some(ref mut r) if r.kind == Kind::Ins => { r.ins.push(call); r.last_vi = vi; }
+                        _ => {
+                            flush(&mut run);
+                            run = Some(Run { kind: Kind::Ins, first_vi: vi, last_vi: vi,
+                                del: (0, 0), ins_at: ref_before[vi], ins: vec![call] });
+                        }
+                    }
+                }
+                _ => flush(&mut run),
+            }
+        }
+        flush(&mut run);
+    }
+
+    let mut records: Vec<IndelRecord> = events.into_iter().map(|((a, ref_allele, alt_allele), (carriers, first, last))| {
+        let mut carrier = vec![false; num_samples];
+        for &si in &carriers { carrier[si] = true; }
+        let genotypes = (0..num_samples).map(|si| {
+            if carrier[si] { return Some(1); }
+            let matches_ref = (first..=last).all(|vi| {
+                let call = geno[vi * num_samples + si];
+                if var_positions[vi].ref_pos.is_none() { is_gap(call) }
+                else { matches!(call, b'A' | b'C' | b'G' | b'T') }
+            });
+            if matches_ref { Some(0) } else { None }
+        }).collect();
+        IndelRecord { column: cols[a], ref_allele, alt_allele, genotypes }
+    }).collect();
+    records.sort_by(|x, y| (x.column, &x.ref_allele, &x.alt_allele).cmp(&(y.column, &y.ref_allele, &y.alt_allele)));
+    IndelCalls { records, unanchored, ref_gap: across_gap }
+}
@@ -0,0 +1,171 @@
+//! SNP-barcode lineage assignment.
+//!
+//! Reads the allele of every record at the positions of a lineage barcode
+//! (position, allele, lineage), scores the fraction of typed barcode sites that
+//! match each lineage, and reports the most specific supported lineage per sample.
+//! Lineage names are hierarchical and dot-separated (`lineage4` → `lineage4.2`).
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::extract::fetch_columns;
+use crate::fasta::FastaRecord;
+use crate::positions::ref_columns;
+use crate::types::{SeqLayout, IO_BUF};
+
+/// One lineage-defining allele.
+pub struct BarcodeSite {
+    /// 0-based reference coordinate.
+    pub pos: usize,
+    pub allele: u8,
+    /// Index into `Barcode::lineages`.
+    pub lineage: usize,
+    /// Alignment column of `pos`, set by `resolve_columns`.
+    pub column: usize,
+}
+
+/// A lineage barcode: lineage names and their defining alleles.
+pub struct Barcode {
+    pub lineages: Vec<String>,
+    pub sites: Vec<BarcodeSite>,
+}
+
+/// Read a barcode TSV with columns `position allele lineage`.
+///
+/// Positions are 1-based reference coordinates. A header line and `#` comments are skipped.
+pub fn read_barcode(path: &str) -> io::Result<Barcode> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open barcode '{}': {}", path, e)))?;
+    let bad = |ln: usize, msg: &str| io::Error::new(io::ErrorKind::InvalidData,
+        format!("Barcode '{}' line {}: {}", path, ln, msg));
+
+    let mut barcode = Barcode { lineages: Vec::new(), sites: Vec::new() };
+    for (li, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() { continue; }
+        let f: Vec<&str> = line.split('\t').map(str::trim).collect();
+        if f.len() < 3 { return Err(bad(li + 1, "expected 3 columns")); }
+        let pos: usize = match f[0].parse() {
+            Ok(p) => p,
+            Err(_) if li == 0 => continue,
+            Err(_) => return Err(bad(li + 1, "invalid position")),
+        };
+        if pos == 0 { return Err(bad(li + 1, "positions are 1-based")); }
+        let allele = match f[1].to_ascii_uppercase().as_bytes() {
+            &[b @ (b'A' | b'C' | b'G' | b'T')] => b,
+            _ => return Err(bad(li + 1, "allele must be a single A, C, G or T")),
+        };
+        let lineage = match barcode.lineages.iter().position(|l| l == f[2]) {
+            Some(i) => i,
+            None => { barcode.lineages.push(f[2].to_string()); barcode.lineages.len() - 1 }
+        };
+        barcode.sites.push(BarcodeSite { pos: pos - 1, allele, lineage, column: 0 });
+    }
+    Ok(barcode)
+}
+
+/// Map barcode positions to alignment columns through the reference (first) sequence.
+pub fn resolve_columns(barcode: &mut Barcode, ref_seq: &[u8]) -> io::Result<()> {
+    let cols = ref_columns(ref_seq);
+    for s in barcode.sites.iter_mut() {
+        s.column = *cols.get(s.pos).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+            format!("Barcode position {} lies beyond the reference length {}.", s.pos + 1, cols.len())))?;
+    }
+    Ok(())
+}
+
+/// Whether lineage `a` is `b` or one of its ancestors.
+fn is_ancestor(a: &str, b: &str) -> bool {
+    b == a || (b.starts_with(a) && b.as_bytes()[a.len()] == b'.')
+}
+
+/// Per-lineage tallies of one sample's barcode calls.
+#[derive(Clone, Copy, Default)]
+struct LineageScore {
+    support: usize,
+    conflict: usize,
+    missing: usize,
+}
+
+impl LineageScore {
+    fn fraction(&self) -> f64 {
+        let typed = self.support + self.conflict;
+        if typed == 0 { 0.0 } else { self.support as f64 / typed as f64 }
+    }
+}
+
+/// Counts reported on stderr after lineage assignment.
+#[derive(Default)]
+pub struct LineageSummary {
+    pub unassigned: usize,
+    pub mixed: usize,
+}
+
+/// Assign a lineage to every record and write the report.
+///
+/// A lineage is supported when at least `min_support` of its typed sites carry the
+/// barcode allele; N, IUPAC codes and gaps count as missing, other bases as
+/// conflicting. The reported lineage is the deepest supported one (ties broken by
+/// support fraction, then matched sites); supported lineages that do not lie on one path of the
+/// hierarchy flag a possible mixed infection.
+///
+/// Columns: sample, lineage, support (fraction), sites (`matched/typed/total`),
+/// mixed (`yes`/`no`), supported (all supported lineages as `name:fraction`).
+pub fn write_lineage_report(
+    records: &[FastaRecord], layout: SeqLayout, upper: &[u8; 256], barcode: &Barcode, min_support: f64, path: &str,
+) -> io::Result<LineageSummary> {
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create lineage report '{}': {}", path, e)))?;
+    let mut w = BufWriter::with_capacity(IO_BUF, out);
+
+    let mut columns: Vec<usize> = barcode.sites.iter().map(|s| s.column).collect();
+    columns.sort_unstable();
+    columns.dedup();
+    let slot: Vec<usize> = barcode.sites.iter()
+        .map(|s| columns.binary_search(&s.column).unwrap_or(0)).collect();
+
+    let mut summary = LineageSummary::default();
+    writeln!(w, "sample\tlineage\tsupport\tsites\tmixed\tsupported")?;
+    let mut calls = vec![0u8; columns.len()];
+    for rec in records {
+        fetch_columns(rec, &columns, layout, upper, &mut calls);
+        let mut scores = vec![LineageScore::default(); barcode.lineages.len()];
+        for (s, &k) in barcode.sites.iter().zip(slot.iter()) {
+            let sc = &mut scores[s.lineage];
+            match calls[k] {
+                c if c == s.allele => sc.support += 1,
+                b'A' | b'C' | b'G' | b'T' => sc.conflict += 1,
+                _ => sc.missing += 1,
+            }
+        }
+
+        let supported: Vec<usize> = (0..scores.len())
+            .filter(|&i| scores[i].support > 0 && scores[i].fraction() >= min_support).collect();
+        let depth = |i: usize| barcode.lineages[i].matches('.').count();
+        let best = supported.iter().copied().max_by(|&a, &b| depth(a).cmp(&depth(b))
+            .then(scores[a].fraction().total_cmp(&scores[b].fraction()))
+            .then(scores[a].support.cmp(&scores[b].support)));
+        let mixed = supported.iter().any(|&a| supported.iter().any(|&b| {
+            let (la, lb) = (&barcode.lineages[a], &barcode.lineages[b]);
+            !is_ancestor(la, lb) && !is_ancestor(lb, la)
+        }));
+
+        w.write_all(rec.id)?;
+        match best {
+            Some(i) => {
+                let sc = &scores[i];
+                write!(w, "\t{}\t{:.3}\t{}/{}/{}", barcode.lineages[i], sc.fraction(),
+                    sc.support, sc.support + sc.conflict, sc.support + sc.conflict + sc.missing)?;
+            }
+            None => { write!(w, "\t.\t.\t.")?; summary.unassigned += 1; }
+        }
+        if mixed { summary.mixed += 1; }
+        let list: Vec<String> = supported.iter()
+            .map(|&i| format!("{}:{:.3}", barcode.lineages[i], scores[i].fraction())).collect();
+        writeln!(w, "\t{}\t{}", if mixed { "yes" } else { "no" },
+            if list.is_empty() { ".".to_string() } else { list.join(";") })?;
+    }
+
+    w.flush()?;
+    Ok(summary)
+}
@@ -1,21 +1,48 @@
+mod annotate;
+mod cache;
+mod catalogue;
+mod dedup;
+mod expand;
 mod extract;
 mod fasta;
+mod flanks;
+mod fromvcf;
+mod indel;
+mod lineage;
+mod partition;
+mod patterns;
+mod positions;
 mod scan;
+mod simd;
 mod types;
 mod vcf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use memmap2::Mmap;
 use std::fs::File;
 use std::io::{self, BufWriter, Write};
 use std::path::Path;
 use std::time::Instant;
 
-use crate::extract::{pass2_extract, ExtractParams};
-use crate::fasta::{get_ref_seq, index_fasta};
-use crate::scan::{analyze, pass1_scan};
+use crate::annotate::{annotate, read_gff, write_annotation_tsv};
+use crate::cache::{index_appended, read_cache, write_cache, Pass1Cache, RecordLoc, SourceFile};
+use crate::catalogue::{read_catalogue, resolve_columns, write_resistance_report};
+use crate::dedup::{write_haplotype_map, Haplotype};
+use crate::expand::expand_alignment;
+use crate::extract::{fetch_block, geno_blocks, pass2_extract, write_record, ExtractParams, ROUND_BYTES};
+use crate::fasta::{get_ref_seq, index_fasta, index_records, read_fai, write_fai, FastaRecord};
+use crate::flanks::write_flanks;
+use crate::indel::call_indels;
+use crate::fromvcf::{calls_bitmask, read_vcf_calls, write_calls_fasta, VcfCalls};
+use crate::lineage::{read_barcode, write_lineage_report};
+use crate::partition::{
+    column_partitions, partition_order, partition_sites, select_columns, write_partition_file, SiteClass,
+};
+use crate::patterns::{compress_patterns, write_patterns};
+use crate::positions::{read_positions, read_sites, write_positions};
+use crate::scan::{analyze, analyze_selected, drop_multiallelic, force_columns, pass1_scan, pass1_scan_masked};
 use crate::types::*;
-use crate::vcf::write_vcf;
+use crate::vcf::{mnp_pair, write_variant_table, write_vcf, VcfOptions, VcfWriter};
 
 // =============================================================================
 // CLI
@@ -26,14 +53,82 @@ use crate::vcf::write_vcf;
     name = "snpick",
     version = env!("CARGO_PKG_VERSION"),
     author = "Paula Ruiz-Rodriguez <paula.ruiz.rodriguez@csic.es>",
-    about = "A fast, memory-efficient tool for extracting variable sites from FASTA alignments."
+    about = "A fast, memory-efficient tool for extracting variable sites from FASTA alignments.",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 struct Args {
-    #[arg(short, long)] fasta: String,
-    #[arg(short, long)] output: String,
+    #[command(subcommand)] command: Option<Command>,
+    #[arg(short, long, required = true)] fasta: Option<String>,
+    #[arg(short, long, required = true)] output: Option<String>,
     #[arg(short = 'g', long)] include_gaps: bool,
+    #[arg(long)] lowercase_as_missing: bool,
+    #[arg(long)] fai: Option<String>,
+    #[arg(long, conflicts_with = "fai")] write_fai: Option<String>,
+    #[arg(long)] cache: Option<String>,
+    #[arg(long, requires = "cache", conflicts_with = "fai")] append: Option<String>,
     #[arg(long)] vcf: bool,
     #[arg(long)] vcf_output: Option<String>,
+    #[arg(long, requires = "include_gaps")] vcf_indels: bool,
+    #[arg(long, conflicts_with_all = ["vcf_indels", "merge_mnps"])] vcf_sites_only: bool,
+    #[arg(long)] variant_table: Option<String>,
+    #[arg(long, value_parser = parse_size)] max_memory: Option<usize>,
+    #[arg(long)] merge_mnps: bool,
+    #[arg(long)] split_multiallelic: bool,
+    #[arg(long)] biallelic_only: bool,
+    #[arg(long)] dedup: bool,
+    #[arg(long, requires = "dedup")] dedup_map: Option<String>,
+    #[arg(long, requires = "dedup")] dedup_n_compatible: bool,
+    #[arg(long)] patterns: Option<String>,
+    #[arg(long)] positions: Option<String>,
+    #[arg(long)] gff: Option<String>,
+    #[arg(long, requires = "gff")] gff_seqid: Option<String>,
+    #[arg(long, requires = "gff")] annotation_output: Option<String>,
+    #[arg(long, requires = "gff")] partition: Option<String>,
+    #[arg(long, requires = "partition", default_value = "GTR+G")] partition_model: String,
+    #[arg(long, value_enum, requires = "gff")] site_class: Option<SiteClass>,
+    #[arg(long)] sites: Option<String>,
+    #[arg(long, requires = "sites", conflicts_with = "site_class")] sites_only: bool,
+    #[arg(long)] flanks: Option<usize>,
+    #[arg(long, requires = "flanks")] flanks_output: Option<String>,
+    #[arg(long)] catalogue: Option<String>,
+    #[arg(long, requires = "catalogue")] catalogue_output: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rebuild a full-length alignment from a reduced FASTA, its position map and the reference
+    Expand(ExpandArgs),
+    /// Build the variable-site alignment and ASC counts from haploid VCFs and a reference
+    FromVcf(FromVcfArgs),
+    /// Assign lineages from a SNP barcode and flag possible mixed infections
+    Lineage(LineageArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ExpandArgs {
+    #[arg(short, long)] fasta: String,
+    #[arg(short, long)] positions: String,
+    #[arg(short, long)] reference: String,
+    #[arg(short, long)] output: String,
+    #[arg(long)] ungapped: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct FromVcfArgs {
+    #[arg(long, required = true)] vcf: Vec<String>,
+    #[arg(short, long)] reference: String,
+    #[arg(short, long)] output: String,
+    #[arg(short = 'g', long)] include_gaps: bool,
+    #[arg(long)] positions: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct LineageArgs {
+    #[arg(short, long)] fasta: String,
+    #[arg(short, long)] barcode: String,
+    #[arg(short, long)] output: String,
+    #[arg(long, default_value_t = 0.8)] min_support: f64,
 }
 
 // =============================================================================
@@ -45,7 +140,10 @@ fn resolve_path(p: &str) -> io::Result<std::path::PathBuf> {
     if path.exists() {
         return std::fs::canonicalize(path);
     }
-    let parent = path.parent().unwrap_or(Path::new("."));
+    let parent = match path.parent() {
+        Some(pp) if !pp.as_os_str().is_empty() => pp,
+        _ => Path::new("."),
+    };
     let parent_abs = std::fs::canonicalize(parent).map_err(|e| {
         io::Error::new(e.kind(), format!("Cannot resolve parent of '{}': {}", p, e))
     })?;
@@ -62,115 +160,617 @@ fn check_paths_differ(a: &str, b: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Parse a byte count with an optional K/M/G/T suffix (powers of 1000).
+fn parse_size(s: &str) -> Result<usize, String> {
+    let t = s.trim().trim_end_matches(['B', 'b']);
+    let (num, mult) = match t.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => match c.to_ascii_uppercase() {
+            'K' => (&t[..i], 1e3), 'M' => (&t[..i], 1e6), 'G' => (&t[..i], 1e9), 'T' => (&t[..i], 1e12),
+            _ => return Err(format!("unknown size suffix in '{}'", s)),
+        },
+        _ => (t, 1.0),
+    };
+    let v: f64 = num.trim().parse().map_err(|_| format!("invalid size '{}'", s))?;
+    if v.is_nan() || v < 1.0 { return Err(format!("size must be at least 1 byte: '{}'", s)); }
+    Ok((v * mult) as usize)
+}
+
+/// Derive a sibling output path: `<dir>/<stem of output>.<ext>`.
+fn sibling_path(output: &str, ext: &str) -> String {
+    let out = Path::new(output);
+    let stem = out.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+    let parent = out.parent().unwrap_or(Path::new("."));
+    parent.join(format!("{}.{}", stem, ext)).to_string_lossy().into_owned()
+}
+
+/// Open and memory-map a non-empty input file.
+fn map_file(path: &str) -> io::Result<Mmap> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open '{}': {}", path, e)))?;
+    let file_len = file.metadata()?.len();
+    if file_len == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Input file '{}' is empty (0 bytes).", path)));
+    }
+    unsafe { Mmap::map(&file).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot memory-map '{}': {}", path, e))) }
+}
+
 // =============================================================================
 // Pipeline
 // =============================================================================
 
 fn run() -> io::Result<()> {
     let args = Args::parse();
+    match args.command {
+        Some(Command::Expand(ref ea)) => run_expand(ea),
+        Some(Command::FromVcf(ref va)) => run_from_vcf(va),
+        Some(Command::Lineage(ref la)) => run_lineage(la),
+        None => run_extract(args),
+    }
+}
+
+/// Sequences listed by name in the soft-mask summary (those with the most masked calls).
+const MASKED_LISTED: usize = 10;
+
+/// Print the site classification summary and ASC fconst line.
+fn report_sites(site_counts: &SiteCounts, seq_length: usize) {
+    eprintln!("[snpick] {} variable, {} constant ({}), {} gap-only, {} all-missing, {} ambiguous-only, {} total.",
+        site_counts.variable, site_counts.constant.total(), site_counts.constant,
+        site_counts.gap_only, site_counts.missing, site_counts.ambiguous, seq_length);
+    if site_counts.gapped_constant > 0 {
+        eprintln!("[snpick] {} constant positions also contain gaps (ignored without -g).", site_counts.gapped_constant);
+    }
+    if site_counts.excluded > 0 {
+        eprintln!("[snpick] {} positions outside the selected site class or panel excluded.", site_counts.excluded);
+    }
+    if site_counts.multiallelic > 0 {
+        eprintln!("[snpick] {} multi-allelic positions dropped (--biallelic-only).", site_counts.multiallelic);
+    }
+    if site_counts.forced > 0 {
+        eprintln!("[snpick] {} non-variable panel sites forced into the output.", site_counts.forced);
+    }
+    eprintln!("[snpick] ASC fconst: {}", site_counts.constant.fconst());
+}
+
+fn run_extract(args: Args) -> io::Result<()> {
+    // clap enforces --fasta/--output when no subcommand is given
+    let (Some(fasta), Some(output)) = (args.fasta.clone(), args.output.clone()) else {
+        unreachable!("--fasta and --output are required");
+    };
     let start = Instant::now();
-    let lookup = build_lookup(args.include_gaps);
-    let upper = build_upper();
+    let mut lookup = build_lookup(args.include_gaps);
+    let mut upper = build_upper();
+    if args.lowercase_as_missing { mask_lowercase(&mut lookup, &mut upper); }
 
-    let do_vcf = args.vcf || args.vcf_output.is_some();
+    let do_vcf = args.vcf || args.vcf_output.is_some() || args.vcf_indels || args.merge_mnps
+        || args.split_multiallelic || args.vcf_sites_only;
 
     // Validate paths
-    check_paths_differ(&args.fasta, &args.output)?;
+    check_paths_differ(&fasta, &output)?;
     let vcf_path = if do_vcf {
-        let vp = args.vcf_output.unwrap_or_else(|| {
-            let out = Path::new(&args.output);
-            let stem = out.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
-            let parent = out.parent().unwrap_or(Path::new("."));
-            parent.join(format!("{}.vcf", stem)).to_string_lossy().into_owned()
-        });
-        check_paths_differ(&args.fasta, &vp)?;
-        check_paths_differ(&args.output, &vp)?;
+        let vp = args.vcf_output.unwrap_or_else(|| sibling_path(&output, "vcf"));
+        check_paths_differ(&fasta, &vp)?;
+        check_paths_differ(&output, &vp)?;
         Some(vp)
     } else { None };
+    let dedup_path = if args.dedup {
+        let dp = args.dedup_map.unwrap_or_else(|| sibling_path(&output, "haplotypes.tsv"));
+        check_paths_differ(&fasta, &dp)?;
+        check_paths_differ(&output, &dp)?;
+        Some(dp)
+    } else { None };
+    let pattern_paths = match args.patterns {
+        Some(ref pp) => {
+            let wp = sibling_path(pp, "weights");
+            let mp = sibling_path(pp, "patterns.tsv");
+            for p in [pp, &wp, &mp] {
+                check_paths_differ(&fasta, p)?;
+                check_paths_differ(&output, p)?;
+            }
+            Some((pp.clone(), wp, mp))
+        }
+        None => None,
+    };
+    if let Some(ref pp) = args.positions {
+        check_paths_differ(&fasta, pp)?;
+        check_paths_differ(&output, pp)?;
+    }
+    let annotation_path = if args.gff.is_some() {
+        let ap = args.annotation_output.clone()
+            .unwrap_or_else(|| sibling_path(&output, "annotation.tsv"));
+        check_paths_differ(&fasta, &ap)?;
+        check_paths_differ(&output, &ap)?;
+        Some(ap)
+    } else { None };
+    if let Some(ref pp) = args.partition {
+        check_paths_differ(&fasta, pp)?;
+        check_paths_differ(&output, pp)?;
+    }
+    let flanks_path = if args.flanks.is_some() {
+        let fp = args.flanks_output.clone()
+            .unwrap_or_else(|| sibling_path(&output, "flanks.fa"));
+        check_paths_differ(&fasta, &fp)?;
+        check_paths_differ(&output, &fp)?;
+        Some(fp)
+    } else { None };
+    let catalogue_path = if args.catalogue.is_some() {
+        let cp = args.catalogue_output.clone()
+            .unwrap_or_else(|| sibling_path(&output, "resistance.tsv"));
+        check_paths_differ(&fasta, &cp)?;
+        check_paths_differ(&output, &cp)?;
+        Some(cp)
+    } else { None };
+    if let Some(ref fp) = args.write_fai {
+        check_paths_differ(&fasta, fp)?;
+        check_paths_differ(&output, fp)?;
+    }
+    if let Some(ref tp) = args.variant_table {
+        check_paths_differ(&fasta, tp)?;
+        check_paths_differ(&output, tp)?;
+    }
+    // Sites-only outputs need per-site counts but not the genotype matrix
+    let collect_geno = (do_vcf && !args.vcf_sites_only) || pattern_paths.is_some();
+    let collect_counts = do_vcf || args.variant_table.is_some();
 
-    // Memory-map input
-    let file = File::open(&args.fasta).map_err(|e| io::Error::new(e.kind(),
-        format!("Cannot open '{}': {}", args.fasta, e)))?;
-    let file_len = file.metadata()?.len();
-    if file_len == 0 {
-        return Err(io::Error::new(io::ErrorKind::InvalidData,
-            format!("Input file '{}' is empty (0 bytes).", args.fasta)));
+    if let Some(ref cp) = args.cache {
+        check_paths_differ(&fasta, cp)?;
+        check_paths_differ(&output, cp)?;
+    }
+    // The cache is reused only if it matches --fasta, the files appended since and
+    // the scan options; --append needs such a cache
+    let fasta_source = match args.cache {
+        Some(_) => Some(SourceFile::stat(&fasta)?),
+        None => None,
+    };
+    let read = match (&args.cache, &fasta_source) {
+        (Some(cp), Some(_)) if Path::new(cp).exists() => match read_cache(cp) {
+            Ok(c) => Some(c),
+            // An unreadable cache is rebuilt like a stale one
+            Err(e) => {
+                eprintln!("[snpick] Warning: {}; rescanning {}.", e, fasta);
+                None
+            }
+        },
+        _ => None,
+    };
+    let stale = read.is_some();
+    let cached = read.filter(|c| fasta_source.as_ref()
+        .is_some_and(|fs| c.matches(fs, args.include_gaps, args.lowercase_as_missing)));
+    if let (Some(ref cp), None) = (&args.cache, &cached) {
+        if args.append.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "--append needs a cache of '{}' and its appended files, unchanged and built with the same options; \
+                run once with --cache first.", fasta)));
+        }
+        if stale {
+            eprintln!("[snpick] Cache {} does not match the input files or options; rescanning {}.", cp, fasta);
+        }
     }
-    let mmap = unsafe { Mmap::map(&file).map_err(|e| io::Error::new(e.kind(),
-        format!("Cannot memory-map '{}': {}", args.fasta, e)))? };
+    if let Some(ref ap) = args.append { check_paths_differ(&fasta, ap)?; }
+
+    // Memory-map input, and the appended files, which are read in place
+    let mmap = map_file(&fasta)?;
     // Hint: pass 1 reads sequentially; OS can prefetch and release pages eagerly
     mmap.advise(memmap2::Advice::Sequential).ok();
     let data = &mmap[..];
+    let mut source_files: Vec<SourceFile> = match cached {
+        Some(ref c) => c.sources.clone(),
+        None => fasta_source.into_iter().collect(),
+    };
+    if let Some(ref ap) = args.append { source_files.push(SourceFile::stat(ap)?); }
+    for sf in &source_files[1..] { check_paths_differ(&output, &sf.path)?; }
+    let appended: Vec<Mmap> = source_files[1..].iter().map(|sf| map_file(&sf.path)).collect::<io::Result<_>>()?;
+    let sources: Vec<&[u8]> = std::iter::once(data).chain(appended.iter().map(|m| &m[..])).collect();
 
-    // Index records
-    let (records, seq_length, layout) = index_fasta(data)?;
+    // Soft-masked calls are counted per record in pass 1 when they are treated as missing
+    let scan = |recs: &[FastaRecord], sl: usize, layout: SeqLayout| if args.lowercase_as_missing {
+        pass1_scan_masked(recs, sl, layout, &lookup)
+    } else {
+        (pass1_scan(recs, sl, layout, &lookup), vec![0; recs.len()])
+    };
+    // Index records and run pass 1: from the cache if it matches the inputs, over
+    // the appended records only after --append, otherwise over everything
+    let (records, seq_length, layout, bitmask, masked, cache_hit) = match cached {
+        Some(c) if args.append.is_some() => {
+            let ap = args.append.as_deref().unwrap_or_default();
+            let mut records = c.records(&sources)?;
+            let (new, new_layout) = index_appended(sources[sources.len() - 1], ap, c.seq_length, &records)?;
+            let mut bitmask = c.bitmask;
+            let (new_bitmask, new_masked) = scan(&new, c.seq_length, new_layout);
+            for (bm, b) in bitmask.iter_mut().zip(new_bitmask) { *bm |= b; }
+            let mut masked: Vec<usize> = c.records.iter().map(|r| r.masked).collect();
+            masked.extend(new_masked);
+            eprintln!("[snpick] Pass 1 for {} cached sequences loaded; scanned {} appended from {}.", records.len(), new.len(), ap);
+            records.extend(new);
+            let layout = SeqLayout {
+                single_line: c.layout.single_line && new_layout.single_line,
+                fixed_width: c.layout.fixed_width && new_layout.fixed_width,
+            };
+            (records, c.seq_length, layout, bitmask, masked, false)
+        }
+        Some(c) => {
+            eprintln!("[snpick] Pass 1 loaded from cache {}.", args.cache.as_deref().unwrap_or_default());
+            let masked = c.records.iter().map(|r| r.masked).collect();
+            (c.records(&sources)?, c.seq_length, c.layout, c.bitmask, masked, true)
+        }
+        None => {
+            let (records, seq_length, layout) = match args.fai {
+                Some(ref fp) => read_fai(data, fp)?,
+                None => index_fasta(data)?,
+            };
+            let (bitmask, masked) = scan(&records, seq_length, layout);
+            (records, seq_length, layout, bitmask, masked, false)
+        }
+    };
+    if let Some(ref fp) = args.write_fai {
+        if sources.len() > 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "Cannot write a .fai index: some sequences were appended from other files than --fasta."));
+        }
+        write_fai(&records, seq_length, layout, fp)?;
+        eprintln!("[snpick] FASTA index written to {}.", fp);
+    }
+    if let (Some(ref cp), false) = (&args.cache, cache_hit) {
+        let source_of = |r: &FastaRecord| sources.iter().position(|&s| std::ptr::eq(s, r.data)).unwrap_or(0);
+        let cache = Pass1Cache {
+            include_gaps: args.include_gaps, lowercase_as_missing: args.lowercase_as_missing,
+            seq_length, layout, sources: source_files,
+            records: records.iter().zip(masked.iter()).map(|(r, &m)| RecordLoc {
+                source: source_of(r), seq_offset: r.seq_offset, line_width: r.line_width, line_stride: r.line_stride, masked: m,
+            }).collect(),
+            bitmask: bitmask.clone(),
+        };
+        write_cache(&cache, cp)?;
+        eprintln!("[snpick] Pass 1 cache written to {}.", cp);
+    }
     let num_samples = records.len();
 
     eprintln!("[snpick] Mapped {} bytes. {} sequences × {} positions.{}",
-        data.len(), num_samples, seq_length,
-        if layout.single_line { "" } else { " (multi-line FASTA)" });
+        sources.iter().map(|s| s.len()).sum::<usize>(), num_samples, seq_length,
+        if layout.single_line { "" }
+        else if layout.fixed_width { " (wrapped FASTA, fixed line width)" }
+        else { " (multi-line FASTA, irregular wrapping)" });
 
-    // Pass 1: bitmask scan
-    let bitmask = pass1_scan(data, &records, seq_length, layout, &lookup);
-    let ref_seq = get_ref_seq(data, &records[0], seq_length, layout);
+    let ref_seq = get_ref_seq(&records[0], seq_length, layout);
+    let masked = if args.lowercase_as_missing { Some(masked) } else { None };
     let t1 = start.elapsed().as_secs_f64();
 
-    let (mut var_positions, site_counts) = analyze(&bitmask, &ref_seq, &lookup, args.include_gaps);
+    // Functional annotation, codon partitions and site classes need the reference codon context
+    let cds = match args.gff {
+        Some(ref gp) => {
+            let ref_len = ref_seq.iter().filter(|&&b| !is_gap(b)).count();
+            Some(read_gff(gp, args.gff_seqid.as_deref(), ref_len)?)
+        }
+        None => None,
+    };
+    let forced = match args.sites {
+        Some(ref sp) => read_sites(sp, &ref_seq)?,
+        None => Vec::new(),
+    };
+    let select = match (args.site_class, &cds) {
+        (Some(class), Some(cds)) => Some(select_columns(&ref_seq, cds, class)),
+        _ if args.sites_only => {
+            let mut sel = vec![false; seq_length];
+            for &c in &forced { sel[c] = true; }
+            Some(sel)
+        }
+        _ => None,
+    };
+
+    let (mut var_positions, mut site_counts) = analyze_selected(
+        &bitmask, &ref_seq, &lookup, args.include_gaps, select.as_deref());
+    if !forced.is_empty() {
+        force_columns(&bitmask, &ref_seq, &lookup, args.include_gaps, select.as_deref(),
+            &forced, &mut var_positions, &mut site_counts);
+    }
+    if args.biallelic_only { drop_multiallelic(&mut var_positions, &mut site_counts); }
     let num_var = var_positions.len();
 
+    let partitions = match (&cds, &args.partition) {
+        (Some(cds), Some(_)) => {
+            let parts = column_partitions(&ref_seq, cds);
+            Some(partition_sites(&bitmask, &parts, &var_positions, select.as_deref()))
+        }
+        _ => None,
+    };
+    let annotations = cds.map(|cds| {
+        let ref_ungapped: Vec<u8> = ref_seq.iter().copied().filter(|&b| !is_gap(b)).collect();
+        annotate(&var_positions, cds, &ref_ungapped)
+    });
+    let column_order = partitions.as_deref().map(partition_order);
+
+    if let (Some(k), Some(ref fp)) = (args.flanks, &flanks_path) {
+        let nearby = write_flanks(&var_positions, &ref_seq, k, &lookup, fp)?;
+        eprintln!("[snpick] Flanks (±{} bp) written to {}; {} sites have other variable sites within the flanks.",
+            k, fp, nearby);
+    }
+
+    // Catalogue positions are reference coordinates, resolved to columns while ref_seq is at hand
+    let catalogue = match args.catalogue {
+        Some(ref cp) => {
+            let (mut entries, skipped) = read_catalogue(cp)?;
+            let mismatch = resolve_columns(&mut entries, &ref_seq)?;
+            eprintln!("[snpick] Catalogue: {} mutations ({} non-SNV skipped, {} REF mismatches).",
+                entries.len(), skipped, mismatch);
+            Some(entries)
+        }
+        None => None,
+    };
+
     drop(bitmask);
-    drop(ref_seq);
+    // Indel calling needs the reference gap pattern after pass 2
+    let indel_ref = if args.vcf_indels { Some(ref_seq) } else { None };
 
-    eprintln!("[snpick] {} variable, {} constant ({}), {} ambiguous-only, {} total.",
-        site_counts.variable, site_counts.constant.total(), site_counts.constant,
-        site_counts.ambiguous, seq_length);
-    eprintln!("[snpick] ASC fconst: {}", site_counts.constant.fconst());
+    report_sites(&site_counts, seq_length);
+    if let Some(ref masked) = masked {
+        let total: usize = masked.iter().sum();
+        let mut by_count: Vec<(usize, usize)> = masked.iter().copied().enumerate().filter(|&(_, n)| n > 0).collect();
+        eprintln!("[snpick] {} soft-masked calls treated as missing in {} sequences.", total, by_count.len());
+        // The most masked sequences only; the rest are summarised
+        by_count.sort_by_key(|&(ri, n)| (std::cmp::Reverse(n), ri));
+        for &(ri, n) in by_count.iter().take(MASKED_LISTED) {
+            eprintln!("[snpick]   {}: {}", String::from_utf8_lossy(records[ri].id), n);
+        }
+        if by_count.len() > MASKED_LISTED {
+            eprintln!("[snpick]   ... and {} more sequences.", by_count.len() - MASKED_LISTED);
+        }
+    }
     eprintln!("[snpick] Pass 1 took {:.2}s.", t1);
 
+    if let (Some(ref an), Some(ref ap)) = (&annotations, &annotation_path) {
+        write_annotation_tsv(an, &var_positions, ap)?;
+        eprintln!("[snpick] {} of {} variable sites fall in {} CDS features. Annotation written to {}.",
+            an.sites.iter().flatten().count(), num_var, an.cds.len(), ap);
+    }
+
+    if let (Some(ref parts), Some(ref pp)) = (&partitions, &args.partition) {
+        for p in parts {
+            eprintln!("[snpick] Partition {}: {} variable, fconst {}.", p.name, p.columns.len(), p.constant.fconst());
+        }
+        write_partition_file(parts, &args.partition_model, pp)?;
+        eprintln!("[snpick] Partition file written to {}.", pp);
+    }
+
+    if let (Some(ref entries), Some(ref cp)) = (&catalogue, &catalogue_path) {
+        write_resistance_report(&records, layout, &upper, entries, cp)?;
+        eprintln!("[snpick] Resistance report written to {}.", cp);
+    }
+
     // Handle zero-variant case
     if num_var == 0 {
         eprintln!("[snpick] No variable positions — writing empty output.");
-        let out = File::create(&args.output)?;
+        let out = File::create(&output)?;
         let mut w = BufWriter::new(out);
-        for rec in &records {
-            w.write_all(b">")?;
-            w.write_all(rec.id)?;
-            if !rec.desc.is_empty() { w.write_all(b" ")?; w.write_all(rec.desc)?; }
-            writeln!(w)?; writeln!(w)?;
+        if let Some(ref dp) = dedup_path {
+            // Every record shares the empty haplotype
+            write_record(&mut w, &records[0], b"")?;
+            let all = Haplotype { representative: 0, members: (0..num_samples).collect(), row: Vec::new() };
+            write_haplotype_map(&[all], &records, dp)?;
+        } else {
+            for rec in &records { write_record(&mut w, rec, b"")?; }
         }
         w.flush()?;
+        if let Some(ref pp) = args.positions { write_positions(&var_positions, None, pp)?; }
         return Ok(());
     }
 
-    // VCF size guard
-    if do_vcf {
-        let geno_bytes = num_var.saturating_mul(num_samples);
-        if geno_bytes > MAX_VCF_GENO_BYTES {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                format!("VCF genotype matrix would require {} GB ({} vars × {} samples). \
-                    Use without --vcf or reduce input.",
-                    geno_bytes / 1_000_000_000, num_var, num_samples)));
-        }
+    // Genotype matrix size guard: the VCF alone can be streamed in blocks, the
+    // pattern compression needs the whole matrix
+    let geno_budget = args.max_memory.unwrap_or(MAX_VCF_GENO_BYTES);
+    let geno_bytes = num_var.saturating_mul(num_samples);
+    let stream_vcf = collect_geno && geno_bytes > geno_budget;
+    if stream_vcf && (pattern_paths.is_some() || indel_ref.is_some()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Genotype matrix would require {:.1} MB ({} vars × {} samples), over the {:.1} MB limit. \
+                --patterns and --vcf-indels need the whole matrix; raise --max-memory or reduce input.",
+                geno_bytes as f64 / 1e6, num_var, num_samples, geno_budget as f64 / 1e6)));
     }
+    let collect_geno = collect_geno && !stream_vcf;
+    // The rows fetched per parallel round count against the same budget: in pass 2
+    // they share it with the whole matrix, when streaming with one block matrix
+    let round_bytes = if stream_vcf { geno_budget / 2 } else if collect_geno { geno_budget - geno_bytes } else { geno_budget }
+        .min(ROUND_BYTES);
+    let block_budget = geno_budget - round_bytes;
 
     // Pass 2: extract variable sites
     let ep = ExtractParams {
-        records: &records, output: &args.output,
-        collect_vcf: do_vcf, lookup: &lookup, upper: &upper, layout,
+        collect_vcf: collect_geno, collect_counts,
+        dedup: args.dedup, n_compatible: args.dedup_n_compatible,
+        column_order: column_order.as_deref(), round_bytes,
+        ..ExtractParams::new(&records, &output, &lookup, &upper, layout)
     };
-    let vcf_geno = pass2_extract(data, &mut var_positions, &ep)?;
+    let p2 = pass2_extract(&mut var_positions, &ep)?;
+
+    // Write column position map
+    if let Some(ref pp) = args.positions {
+        write_positions(&var_positions, column_order.as_deref(), pp)?;
+        eprintln!("[snpick] Position map written to {}.", pp);
+    }
+
+    // Write haplotype map
+    if let (Some(ref haps), Some(ref dp)) = (&p2.haplotypes, &dedup_path) {
+        write_haplotype_map(haps, &records, dp)?;
+        eprintln!("[snpick] Haplotype map written to {}.", dp);
+    }
 
     // Write VCF
-    if let (Some(ref geno), Some(ref vp)) = (&vcf_geno, &vcf_path) {
-        write_vcf(geno, num_samples, &var_positions, vp, &records, seq_length)?;
+    if let Some(ref vp) = vcf_path {
+        let geno = p2.vcf_geno.as_deref().unwrap_or(&[]);
+        let indels = indel_ref.map(|rs| call_indels(geno, num_samples, &var_positions, &rs));
+        if let Some(ref ic) = indels {
+            eprintln!("[snpick] {} indels called from gap runs ({} at the reference start and {} across reference gaps skipped).",
+                ic.records.len(), ic.unanchored, ic.ref_gap);
+        }
+        let opts = VcfOptions {
+            annotations: annotations.as_ref(),
+            indels: indels.as_ref().map(|ic| ic.records.as_slice()),
+            merge_mnps: args.merge_mnps,
+            split_multiallelic: args.split_multiallelic,
+            sites_only: args.vcf_sites_only,
+        };
+        if stream_vcf {
+            // Re-read the variable columns from the mmap one block at a time
+            let columns: Vec<usize> = var_positions.iter().map(|v| v.index).collect();
+            // With --merge-mnps, blocks end only where adjacent columns do not co-vary
+            let mut pair = Vec::new();
+            let blocks = geno_blocks(&var_positions, num_samples, block_budget, |e| {
+                let (va, vb) = (&var_positions[e - 1], &var_positions[e]);
+                let annotated = |i: usize| annotations.as_ref().is_some_and(|an| an.sites[i].is_some());
+                if !args.merge_mnps || vb.index != va.index + 1 || annotated(e - 1) || annotated(e) { return Ok(false); }
+                fetch_block(&records, &columns[e - 1..=e], layout, &upper, round_bytes, &mut pair)?;
+                Ok(mnp_pair(&pair[..num_samples], &pair[num_samples..], va, vb))
+            })?;
+            let mut vw = VcfWriter::create(vp, &records, seq_length, &var_positions, &opts)?;
+            let mut block_geno = Vec::new();
+            for b in &blocks {
+                fetch_block(&records, &columns[b.clone()], layout, &upper, round_bytes, &mut block_geno)?;
+                vw.write_sites(&block_geno, b.clone())?;
+            }
+            vw.finish()?;
+            eprintln!("[snpick] VCF streamed in {} blocks ({:.1} MB genotype budget).", blocks.len(), block_budget as f64 / 1e6);
+        } else {
+            write_vcf(geno, num_samples, &var_positions, vp, &records, seq_length, &opts)?;
+        }
         eprintln!("[snpick] VCF written to {}.", vp);
     }
 
+    if let Some(ref tp) = args.variant_table {
+        write_variant_table(&var_positions, tp)?;
+        eprintln!("[snpick] Variant table written to {}.", tp);
+    }
+
+    // Write compressed site patterns (over haplotype representatives when deduplicating)
+    if let (Some(ref geno), Some((ref pp, ref wp, ref mp))) = (&p2.vcf_geno, &pattern_paths) {
+        let rows: Vec<usize> = match p2.haplotypes {
+            Some(ref hs) => hs.iter().map(|h| h.representative).collect(),
+            None => (0..num_samples).collect(),
+        };
+        let sp = compress_patterns(geno, num_samples, num_var, &rows, column_order.as_deref());
+        write_patterns(&sp, geno, num_samples, &rows, &records, &var_positions, pp, wp, mp)?;
+        eprintln!("[snpick] {} variable columns compressed into {} patterns. Written to {} (weights: {}).",
+            num_var, sp.weights.len(), pp, wp);
+    }
+
     eprintln!("[snpick] Done in {:.2}s. {} vars from {} seqs × {} pos.",
         start.elapsed().as_secs_f64(), num_var, num_samples, seq_length);
     Ok(())
 }
 
+fn run_expand(args: &ExpandArgs) -> io::Result<()> {
+    let start = Instant::now();
+    for input in [&args.fasta, &args.positions, &args.reference] {
+        check_paths_differ(input, &args.output)?;
+    }
+
+    let mmap = map_file(&args.fasta)?;
+    // A reduced alignment without variable sites has empty sequences
+    let (records, seq_length, layout) = index_records(&mmap, true)?;
+    let columns = read_positions(&args.positions)?;
+    if columns.len() != seq_length {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Position map '{}' has {} columns but '{}' has {} positions.",
+                args.positions, columns.len(), args.fasta, seq_length)));
+    }
+
+    let ref_mmap = map_file(&args.reference)?;
+    let (ref_records, ref_length, ref_layout) = index_fasta(&ref_mmap)?;
+    let mut reference = get_ref_seq(&ref_records[0], ref_length, ref_layout);
+
+    // Aligned mode places columns by alignment position; ungapped mode places them
+    // by reference coordinate and drops columns that are insertions relative to it.
+    let targets: Vec<Option<usize>> = if args.ungapped {
+        reference.retain(|&b| !is_gap(b));
+        columns.iter().map(|c| c.ref_pos).collect()
+    } else {
+        columns.iter().map(|c| Some(c.index)).collect()
+    };
+    if let Some(&p) = targets.iter().flatten().find(|&&p| p >= reference.len()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Position {} lies beyond the reference length {}{}.", p + 1, reference.len(),
+                if args.ungapped { "" } else { " (use --ungapped for an unaligned reference)" })));
+    }
+    let dropped = targets.iter().filter(|t| t.is_none()).count();
+    if dropped > 0 {
+        eprintln!("[snpick] {} columns are insertions relative to the reference and were dropped.", dropped);
+    }
+
+    expand_alignment(&records, seq_length, layout, &reference, &targets, &args.output)?;
+    eprintln!("[snpick] Expanded {} sequences × {} columns to {} positions in {:.2}s. Written to {}.",
+        records.len(), seq_length, reference.len(), start.elapsed().as_secs_f64(), args.output);
+    Ok(())
+}
+
+fn run_from_vcf(args: &FromVcfArgs) -> io::Result<()> {
+    let start = Instant::now();
+    let lookup = build_lookup(args.include_gaps);
+    let upper = build_upper();
+    for input in args.vcf.iter().chain([&args.reference]) {
+        check_paths_differ(input, &args.output)?;
+        if let Some(ref pp) = args.positions { check_paths_differ(input, pp)?; }
+    }
+
+    let ref_mmap = map_file(&args.reference)?;
+    let (ref_records, seq_length, ref_layout) = index_fasta(&ref_mmap)?;
+    let reference = get_ref_seq(&ref_records[0], seq_length, ref_layout);
+
+    let mut calls = VcfCalls::default();
+    for vp in &args.vcf { read_vcf_calls(vp, &reference, &mut calls)?; }
+    calls.finish();
+    if calls.samples.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No samples found in the VCF input."));
+    }
+    eprintln!("[snpick] Read {} samples from {} VCF(s) against {} positions.",
+        calls.samples.len(), args.vcf.len(), seq_length);
+    if calls.skipped > 0 {
+        eprintln!("[snpick] Skipped {} non-substitution records (indels, symbolic alleles).", calls.skipped);
+    }
+    if calls.ref_mismatch > 0 {
+        eprintln!("[snpick] Warning: {} records have a REF allele that differs from the reference.", calls.ref_mismatch);
+    }
+    if calls.conflicts > 0 {
+        eprintln!("[snpick] Warning: {} calls with conflicting ALT alleles across records set to N.", calls.conflicts);
+    }
+
+    let bitmask = calls_bitmask(&calls, &reference, &lookup);
+    let (var_positions, site_counts) = analyze(&bitmask, &reference, &lookup, args.include_gaps);
+    report_sites(&site_counts, seq_length);
+
+    write_calls_fasta(&calls, &reference, &var_positions, &upper, &args.output)?;
+    if let Some(ref pp) = args.positions {
+        write_positions(&var_positions, None, pp)?;
+        eprintln!("[snpick] Position map written to {}.", pp);
+    }
+    eprintln!("[snpick] Done in {:.2}s. {} vars from {} samples. Written to {}.",
+        start.elapsed().as_secs_f64(), var_positions.len(), calls.samples.len(), args.output);
+    Ok(())
+}
+
+fn run_lineage(args: &LineageArgs) -> io::Result<()> {
+    let start = Instant::now();
+    let upper = build_upper();
+    for input in [&args.fasta, &args.barcode] {
+        check_paths_differ(input, &args.output)?;
+    }
+    if !(0.0..=1.0).contains(&args.min_support) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("--min-support must be between 0 and 1, got {}.", args.min_support)));
+    }
+
+    let mmap = map_file(&args.fasta)?;
+    let (records, seq_length, layout) = index_fasta(&mmap)?;
+    let ref_seq = get_ref_seq(&records[0], seq_length, layout);
+    let mut barcode = read_barcode(&args.barcode)?;
+    lineage::resolve_columns(&mut barcode, &ref_seq)?;
+    eprintln!("[snpick] Barcode: {} sites for {} lineages.", barcode.sites.len(), barcode.lineages.len());
+
+    let summary = write_lineage_report(&records, layout, &upper, &barcode, args.min_support, &args.output)?;
+    eprintln!("[snpick] {} of {} samples unassigned, {} possibly mixed.",
+        summary.unassigned, records.len(), summary.mixed);
+    eprintln!("[snpick] Done in {:.2}s. Written to {}.", start.elapsed().as_secs_f64(), args.output);
+    Ok(())
+}
+
 fn main() {
     if let Err(e) = run() {
         eprintln!("[snpick] Error: {}", e);
@@ -185,7 +785,7 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::extract::ExtractParams;
+    use crate::extract::{fetch_columns, ExtractParams};
     use crate::fasta::{get_ref_seq, index_fasta};
     use crate::scan::{analyze, pass1_scan};
     use crate::vcf::write_vcf;
@@ -223,7 +823,7 @@ mod tests {
         let m = setup(&p);
         let lk = build_lookup(false);
         let (recs, sl, layout) = index_fasta(&m).unwrap();
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
         assert_eq!(bm[2], BIT_G | BIT_C);
         std::fs::remove_file(&p).ok();
     }
@@ -235,11 +835,11 @@ mod tests {
         let lk = build_lookup(false);
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
-        let ep = ExtractParams { records: &recs, output: o, collect_vcf: false, lookup: &lk, upper: &up, layout };
-        pass2_extract(&m, &mut v, &ep).unwrap();
+        let ep = ExtractParams::new(&recs, o, &lk, &up, layout);
+        pass2_extract(&mut v, &ep).unwrap();
         let c = std::fs::read_to_string(o).unwrap();
         let l: Vec<&str> = c.lines().collect();
         assert_eq!(l[1], "TC"); assert_eq!(l[3], "TT"); assert_eq!(l[5], "CC");
@@ -253,17 +853,17 @@ mod tests {
         let lk = build_lookup(false);
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
-        let ep = ExtractParams { records: &recs, output: fo, collect_vcf: true, lookup: &lk, upper: &up, layout };
-        let g = pass2_extract(&m, &mut v, &ep).unwrap().unwrap();
-        write_vcf(&g, recs.len(), &v, vo, &recs, sl).unwrap();
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions::default()).unwrap();
         let c = std::fs::read_to_string(vo).unwrap();
         let dl: Vec<&str> = c.lines().filter(|l| !l.starts_with('#')).collect();
         let f: Vec<&str> = dl[0].split('\t').collect();
         assert_eq!(f[3], "A"); assert_eq!(f[4], "C,G,T");
-        assert_eq!(f[7], "NS=4"); assert_eq!(f[9], "0"); assert_eq!(f[12], "3");
+        assert_eq!(f[7], "NS=4;AC=1,1,1;AN=4;AF=0.25,0.25,0.25;NMISS=0;NGAP=0"); assert_eq!(f[9], "0"); assert_eq!(f[12], "3");
         std::fs::remove_file(&p).ok(); std::fs::remove_file(fo).ok(); std::fs::remove_file(vo).ok();
     }
 
@@ -274,16 +874,16 @@ mod tests {
         let lk = build_lookup(false);
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
-        let ep = ExtractParams { records: &recs, output: fo, collect_vcf: true, lookup: &lk, upper: &up, layout };
-        let g = pass2_extract(&m, &mut v, &ep).unwrap().unwrap();
-        write_vcf(&g, recs.len(), &v, vo, &recs, sl).unwrap();
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions::default()).unwrap();
         let c = std::fs::read_to_string(vo).unwrap();
         let dl: Vec<&str> = c.lines().filter(|l| !l.starts_with('#')).collect();
         let f: Vec<&str> = dl[0].split('\t').collect();
-        assert_eq!(f[7], "NS=2"); assert_eq!(f[11], ".");
+        assert_eq!(f[7], "NS=2;AC=1;AN=2;AF=0.5;NMISS=1;NGAP=0"); assert_eq!(f[11], ".");
         std::fs::remove_file(&p).ok(); std::fs::remove_file(fo).ok(); std::fs::remove_file(vo).ok();
     }
 
@@ -294,11 +894,11 @@ mod tests {
         let lk = build_lookup(false);
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
-        let ep = ExtractParams { records: &recs, output: o, collect_vcf: false, lookup: &lk, upper: &up, layout };
-        pass2_extract(&m, &mut v, &ep).unwrap();
+        let ep = ExtractParams::new(&recs, o, &lk, &up, layout);
+        pass2_extract(&mut v, &ep).unwrap();
         let c = std::fs::read_to_string(o).unwrap();
         assert!(c.contains(">s1 some description"));
         assert!(c.contains(">s2 another desc"));
@@ -311,14 +911,32 @@ mod tests {
         let lk_no = build_lookup(false);
         let lk_yes = build_lookup(true);
         let (recs, sl, layout) = index_fasta(&m).unwrap();
-        let bm1 = pass1_scan(&m, &recs, sl, layout, &lk_no);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm1 = pass1_scan(&recs, sl, layout, &lk_no);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (v1, _) = analyze(&bm1, &rs, &lk_no, false);
         assert!(v1.is_empty());
-        let bm2 = pass1_scan(&m, &recs, sl, layout, &lk_yes);
+        let bm2 = pass1_scan(&recs, sl, layout, &lk_yes);
         let (v2, _) = analyze(&bm2, &rs, &lk_yes, true);
         assert_eq!(v2.len(), 1);
         std::fs::remove_file(&p).ok();
+        // With -g, '.' is the same gap allele as '-' in pass 1, REF and the VCF genotypes
+        let p = tmp("gapg", ">ref\nA.GC\n>s1\nA-GC\n>s2\nATGC\n");
+        let fo = "/tmp/snpick_t_gapg_out.fa"; let vo = "/tmp/snpick_t_gapg.vcf";
+        let m = setup(&p);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk_yes);
+        let (mut v, _) = analyze(&bm, &get_ref_seq(&recs[0], sl, layout), &lk_yes, true);
+        assert_eq!((v.len(), v[0].ref_base, v[0].alt_bases.as_slice()), (1, b'-', &b"T"[..]));
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk_yes, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions::default()).unwrap();
+        let c = std::fs::read_to_string(vo).unwrap();
+        let f: Vec<&str> = c.lines().find(|l| !l.starts_with('#')).unwrap().split('\t').collect();
+        assert_eq!(f[3..5], ["-", "T"]);
+        assert_eq!(f[7], "NS=3;AC=1;AN=3;AF=0.3333;NMISS=0;NGAP=2");
+        assert_eq!(f[9..], ["0", "0", "1"]);
+        for f in [p.as_str(), fo, vo] { std::fs::remove_file(f).ok(); }
     }
 
     #[test] fn test_paths() {
@@ -328,6 +946,12 @@ mod tests {
         std::fs::remove_file(&p).ok();
     }
 
+    #[test] fn test_parse_size() {
+        assert_eq!(parse_size("1.5G"), Ok(1_500_000_000));
+        assert_eq!(parse_size("200mb"), Ok(200_000_000));
+        assert!(parse_size("0").is_err() && parse_size("5X").is_err());
+    }
+
     #[test] fn test_multiline_pass1() {
         let p = tmp("mlg1", ">s1\nAT\nGC\n>s2\nAT\nCC\n");
         let m = setup(&p);
@@ -335,8 +959,8 @@ mod tests {
         let (recs, sl, layout) = index_fasta(&m).unwrap();
         assert_eq!(sl, 4);
         assert!(!layout.single_line);
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (v, _) = analyze(&bm, &rs, &lk, false);
         assert_eq!(v.len(), 1);
         assert_eq!(v[0].index, 2);
@@ -350,11 +974,11 @@ mod tests {
         let lk = build_lookup(false);
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
-        let ep = ExtractParams { records: &recs, output: o, collect_vcf: false, lookup: &lk, upper: &up, layout };
-        pass2_extract(&m, &mut v, &ep).unwrap();
+        let ep = ExtractParams::new(&recs, o, &lk, &up, layout);
+        pass2_extract(&mut v, &ep).unwrap();
         let c = std::fs::read_to_string(o).unwrap();
         let l: Vec<&str> = c.lines().collect();
         assert_eq!(l[1], "G"); assert_eq!(l[3], "C");
@@ -368,12 +992,12 @@ mod tests {
         let lk = build_lookup(false);
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
-        let ep = ExtractParams { records: &recs, output: fo, collect_vcf: true, lookup: &lk, upper: &up, layout };
-        let g = pass2_extract(&m, &mut v, &ep).unwrap().unwrap();
-        write_vcf(&g, recs.len(), &v, vo, &recs, sl).unwrap();
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions::default()).unwrap();
         let c = std::fs::read_to_string(fo).unwrap();
         let l: Vec<&str> = c.lines().collect();
         assert_eq!(l[1], "AG"); assert_eq!(l[3], "AC"); assert_eq!(l[5], "CG");
@@ -391,12 +1015,12 @@ mod tests {
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
         assert_eq!(sl, 7); assert!(!layout.single_line);
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
         assert_eq!(v.len(), 1); assert_eq!(v[0].index, 4);
-        let ep = ExtractParams { records: &recs, output: o, collect_vcf: false, lookup: &lk, upper: &up, layout };
-        pass2_extract(&m, &mut v, &ep).unwrap();
+        let ep = ExtractParams::new(&recs, o, &lk, &up, layout);
+        pass2_extract(&mut v, &ep).unwrap();
         let c = std::fs::read_to_string(o).unwrap();
         let l: Vec<&str> = c.lines().collect();
         assert_eq!(l[1], "A"); assert_eq!(l[3], "C");
@@ -411,12 +1035,12 @@ mod tests {
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
         assert_eq!(sl, 8); assert!(!layout.single_line);
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
         assert_eq!(v.len(), 1); assert_eq!(v[0].index, 6);
-        let ep = ExtractParams { records: &recs, output: o, collect_vcf: false, lookup: &lk, upper: &up, layout };
-        pass2_extract(&m, &mut v, &ep).unwrap();
+        let ep = ExtractParams::new(&recs, o, &lk, &up, layout);
+        pass2_extract(&mut v, &ep).unwrap();
         let c = std::fs::read_to_string(o).unwrap();
         let l: Vec<&str> = c.lines().collect();
         assert_eq!(l[1], "G"); assert_eq!(l[3], "C");
@@ -433,14 +1057,14 @@ mod tests {
         let (recs, sl, layout) = index_fasta(&m).unwrap();
         assert_eq!(sl, 4);
         assert!(!layout.single_line);
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
         assert_eq!(v.len(), 1);
         assert_eq!(v[0].index, 2);
         let o = "/tmp/snpick_t_crlfml_out.fa";
-        let ep = ExtractParams { records: &recs, output: o, collect_vcf: false, lookup: &lk, upper: &up, layout };
-        pass2_extract(&m, &mut v, &ep).unwrap();
+        let ep = ExtractParams::new(&recs, o, &lk, &up, layout);
+        pass2_extract(&mut v, &ep).unwrap();
         let c = std::fs::read_to_string(o).unwrap();
         let l: Vec<&str> = c.lines().collect();
         assert_eq!(l[1], "G"); assert_eq!(l[3], "C");
@@ -454,8 +1078,8 @@ mod tests {
         let lk = build_lookup(false);
         let (recs, sl, layout) = index_fasta(&m).unwrap();
         assert_eq!(recs.len(), 1);
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (v, sc) = analyze(&bm, &rs, &lk, false);
         assert!(v.is_empty());
         assert_eq!(sc.constant.total(), 4);
@@ -471,19 +1095,889 @@ mod tests {
         let up = build_upper();
         let (recs, sl, layout) = index_fasta(&m).unwrap();
         assert_eq!(recs.len(), 2);
-        let bm = pass1_scan(&m, &recs, sl, layout, &lk);
-        let rs = get_ref_seq(&m, &recs[0], sl, layout);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
         let (mut v, _) = analyze(&bm, &rs, &lk, false);
         assert_eq!(v.len(), 1);
         let o = "/tmp/snpick_t_noeof_out.fa";
-        let ep = ExtractParams { records: &recs, output: o, collect_vcf: false, lookup: &lk, upper: &up, layout };
-        pass2_extract(&m, &mut v, &ep).unwrap();
+        let ep = ExtractParams::new(&recs, o, &lk, &up, layout);
+        pass2_extract(&mut v, &ep).unwrap();
         let c = std::fs::read_to_string(o).unwrap();
         let l: Vec<&str> = c.lines().collect();
         assert_eq!(l[1], "G"); assert_eq!(l[3], "C");
         std::fs::remove_file(&p).ok(); std::fs::remove_file(o).ok();
     }
 
+    #[test] fn test_dedup() {
+        let p = tmp("ddg", ">s1\nATGC\n>s2\nATCC\n>s3\nATGC\n>s4\nATNC\n");
+        let o = "/tmp/snpick_t_ddg_out.fa"; let mo = "/tmp/snpick_t_ddg.tsv";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        let ep = ExtractParams { dedup: true, ..ExtractParams::new(&recs, o, &lk, &up, layout) };
+        let h = pass2_extract(&mut v, &ep).unwrap().haplotypes.unwrap();
+        assert_eq!(h.len(), 3);
+        assert_eq!(h[0].members, vec![0, 2]);
+        write_haplotype_map(&h, &recs, mo).unwrap();
+        let c = std::fs::read_to_string(mo).unwrap();
+        let l: Vec<&str> = c.lines().collect();
+        assert_eq!(l[1], "hap1\ts1\t2\ts1,s3");
+        let f = std::fs::read_to_string(o).unwrap();
+        assert_eq!(f, ">s1\nG\n>s2\nC\n>s4\nN\n");
+        std::fs::remove_file(&p).ok(); std::fs::remove_file(o).ok(); std::fs::remove_file(mo).ok();
+    }
+
+    #[test] fn test_dedup_n_compatible() {
+        let p = tmp("ddng", ">s1\nANGC\n>s2\nATCC\n>s3\nATGC\n>s4\nAANC\n");
+        let o = "/tmp/snpick_t_ddng_out.fa";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        let ep = ExtractParams { dedup: true, n_compatible: true, ..ExtractParams::new(&recs, o, &lk, &up, layout) };
+        let h = pass2_extract(&mut v, &ep).unwrap().haplotypes.unwrap();
+        // s3 (fully called) represents s1 (NG); s4 (AN) conflicts with both
+        assert_eq!(h.len(), 3);
+        assert_eq!(h[0].members, vec![1]);
+        assert_eq!(h[1].representative, 2); assert_eq!(h[1].members, vec![0, 2]);
+        assert_eq!(h[2].members, vec![3]);
+        let f = std::fs::read_to_string(o).unwrap();
+        assert_eq!(f, ">s2\nTC\n>s3\nTG\n>s4\nAN\n");
+        std::fs::remove_file(&p).ok(); std::fs::remove_file(o).ok();
+    }
+
+    #[test] fn test_patterns() {
+        let p = tmp("patg", ">ref\nACGAA\n>s1\nCCGCA\n>s2\nACGAT\n>s3\nCCGCA\n");
+        let fo = "/tmp/snpick_t_patg_out.fa"; let po = "/tmp/snpick_t_patg_pat.fa";
+        let wo = "/tmp/snpick_t_patg.weights"; let mo = "/tmp/snpick_t_patg.tsv";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        let rows: Vec<usize> = (0..recs.len()).collect();
+        let sp = compress_patterns(&g, recs.len(), v.len(), &rows, None);
+        // Columns 1 and 4 share the pattern ACAC; column 5 is distinct
+        assert_eq!(sp.weights, vec![2, 1]);
+        assert_eq!(sp.column_pattern, vec![0, 0, 1]);
+        write_patterns(&sp, &g, recs.len(), &rows, &recs, &v, po, wo, mo).unwrap();
+        let c = std::fs::read_to_string(po).unwrap();
+        let l: Vec<&str> = c.lines().collect();
+        assert_eq!(l[1], "AA"); assert_eq!(l[3], "CA"); assert_eq!(l[5], "AT");
+        assert_eq!(std::fs::read_to_string(wo).unwrap(), "2\n1\n");
+        let mc = std::fs::read_to_string(mo).unwrap();
+        assert_eq!(mc.lines().nth(2).unwrap(), "2\t4\t1");
+        // Pattern columns follow the output column order (e.g. grouped by partition)
+        let sp = compress_patterns(&g, recs.len(), v.len(), &rows, Some(&[2, 0, 1]));
+        assert_eq!((&sp.first_column, &sp.weights, &sp.column_pattern), (&vec![2, 0], &vec![1, 2], &vec![0, 1, 1]));
+        write_patterns(&sp, &g, recs.len(), &rows, &recs, &v, po, wo, mo).unwrap();
+        let mc = std::fs::read_to_string(mo).unwrap();
+        assert_eq!(mc.lines().skip(1).collect::<Vec<_>>(), vec!["1\t5\t1", "2\t1\t2", "3\t4\t2"]);
+        assert_eq!(std::fs::read_to_string(po).unwrap().lines().nth(1).unwrap(), "AA");
+        for f in [p.as_str(), fo, po, wo, mo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_positions() {
+        let p = tmp("posg", ">ref\nA-TGC\n>s1\nAATCC\n>s2\nA-TGG\n");
+        let po = "/tmp/snpick_t_posg.tsv";
+        let m = setup(&p);
+        let lk = build_lookup(true);
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (v, _) = analyze(&bm, &rs, &lk, true);
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[0].ref_pos, None);
+        assert_eq!(v[1].ref_pos, Some(2));
+        write_positions(&v, None, po).unwrap();
+        let c = std::fs::read_to_string(po).unwrap();
+        let l: Vec<&str> = c.lines().collect();
+        assert_eq!(l[1], "1\t2\t.\t-\tA");
+        assert_eq!(l[2], "2\t4\t3\tG\tC");
+        assert_eq!(l[3], "3\t5\t4\tC\tG");
+        std::fs::remove_file(&p).ok(); std::fs::remove_file(po).ok();
+    }
+
+    #[test] fn test_expand() {
+        let p = tmp("expg", ">ref\nA-TGC\n>s1\nAATCC\n>s2\nA-TGG\n");
+        let fo = "/tmp/snpick_t_expg_out.fa"; let po = "/tmp/snpick_t_expg.tsv";
+        let eo = "/tmp/snpick_t_expg_full.fa";
+        let m = setup(&p);
+        let lk = build_lookup(true);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, true);
+        let ep = ExtractParams::new(&recs, fo, &lk, &up, layout);
+        pass2_extract(&mut v, &ep).unwrap();
+        write_positions(&v, None, po).unwrap();
+
+        let cols = read_positions(po).unwrap();
+        let rm = setup(fo);
+        let (rr, rl, rlay) = index_fasta(&rm).unwrap();
+        // Aligned reference restores the original alignment exactly
+        let t: Vec<Option<usize>> = cols.iter().map(|c| Some(c.index)).collect();
+        expand_alignment(&rr, rl, rlay, b"A-TGC", &t, eo).unwrap();
+        assert_eq!(std::fs::read_to_string(eo).unwrap(), std::fs::read_to_string(&p).unwrap());
+        // Ungapped reference drops the insertion column
+        let t: Vec<Option<usize>> = cols.iter().map(|c| c.ref_pos).collect();
+        expand_alignment(&rr, rl, rlay, b"ATGC", &t, eo).unwrap();
+        let c = std::fs::read_to_string(eo).unwrap();
+        let l: Vec<&str> = c.lines().collect();
+        assert_eq!(l[3], "ATCC"); assert_eq!(l[5], "ATGG");
+        // Without variable sites the reduced sequences are empty and expand to the reference
+        let rp = tmp("expg_empty", ">s1\n\n>s2 d\n\n");
+        let rm = setup(&rp);
+        assert!(index_fasta(&rm).is_err());
+        let (rr, rl, rlay) = index_records(&rm, true).unwrap();
+        assert_eq!((rr.len(), rl), (2, 0));
+        expand_alignment(&rr, rl, rlay, b"ATGC", &[], eo).unwrap();
+        assert_eq!(std::fs::read_to_string(eo).unwrap(), ">s1\nATGC\n>s2 d\nATGC\n");
+        for f in [p.as_str(), rp.as_str(), fo, po, eo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_from_vcf() {
+        // Equivalent alignment: ref ATGCA; s1 CTGCA; s2 ATGCN; s3 CTGTA
+        let p = tmp("fvg", ">ref\nATGCA\n>s1\nCTGCA\n>s2\nATGCN\n>s3\nCTGTA\n");
+        let vo = "/tmp/snpick_t_fvg.vcf"; let o = "/tmp/snpick_t_fvg_out.fa";
+        std::fs::write(vo, "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1\ts2\ts3\n\
+            1\t1\t.\tA\tC\t.\tPASS\t.\tGT\t1\t0\t1/1\n\
+            1\t3\t.\tG\tGA\t.\tPASS\t.\tGT\t1\t0\t0\n\
+            1\t4\t.\tC\tT\t.\tPASS\t.\tGT\t0\t0\t1\n\
+            1\t5\t.\tA\tG\t.\tPASS\t.\tGT\t0\t.\t0/1\n").unwrap();
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let reference = get_ref_seq(&recs[0], sl, layout);
+        let mut calls = VcfCalls::default();
+        read_vcf_calls(vo, &reference, &mut calls).unwrap();
+        // The same samples again, from a second VCF, are duplicates
+        let mut twice = VcfCalls::default();
+        read_vcf_calls(vo, &reference, &mut twice).unwrap();
+        assert!(read_vcf_calls(vo, &reference, &mut twice).is_err());
+        calls.finish();
+        assert_eq!(calls.skipped, 1);
+        let bm = calls_bitmask(&calls, &reference, &lk);
+        let (v, sc) = analyze(&bm, &reference, &lk, false);
+        // Pass 1 over the samples of the equivalent alignment gives the same counts
+        let (_, sc_aln) = analyze(&pass1_scan(&recs[1..], sl, layout, &lk), &reference, &lk, false);
+        assert_eq!(sc.constant.fconst(), sc_aln.constant.fconst());
+        assert_eq!(sc.variable, sc_aln.variable);
+        assert_eq!(v.len(), 2);
+        write_calls_fasta(&calls, &reference, &v, &up, o).unwrap();
+        assert_eq!(std::fs::read_to_string(o).unwrap(), ">s1\nCC\n>s2\nAC\n>s3\nCT\n");
+        // Split rows at one position: calls beat missing, ALT beats REF, two ALTs conflict
+        std::fs::write(vo, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tt1\tt2\tt3\tt4\tt5\n\
+            1\t2\t.\tT\tC\t.\tPASS\t.\tGT\t1\t.\t1\t.\t0\n\
+            1\t2\t.\tT\tG\t.\tPASS\t.\tGT\t.\t0\t1\t.\t1\n").unwrap();
+        let mut calls = VcfCalls::default();
+        read_vcf_calls(vo, &reference, &mut calls).unwrap();
+        calls.finish();
+        assert_eq!(calls.calls, vec![vec![(1, b'C')], vec![], vec![(1, b'N')], vec![(1, b'N')], vec![(1, b'G')]]);
+        assert_eq!(calls.conflicts, 1);
+        for f in [p.as_str(), vo, o] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_gff_annotation() {
+        // + strand CDS 1-9 (ATG GCA TAA), - strand CDS 10-15 (reverse complement of ATG GCC)
+        let p = tmp("gffg", ">ref\nATGGCATAAGGCCAT\n>s1\nATGGCGTGAGGCCAC\n");
+        let go = "/tmp/snpick_t_gffg.gff"; let ao = "/tmp/snpick_t_gffg.tsv";
+        std::fs::write(go, "##gff-version 3\n\
+            chr\t.\tCDS\t1\t9\t.\t+\t0\tID=cds1;locus_tag=g1\n\
+            chr\t.\tCDS\t10\t15\t.\t-\t0\tID=cds2;Name=g2\n").unwrap();
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (v, _) = analyze(&bm, &rs, &lk, false);
+        let an = annotate(&v, read_gff(go, None, sl).unwrap(), &rs);
+        assert_eq!(v.len(), 3);
+        // GCA→GCG: Ala synonymous, codon position 3
+        let a0 = an.sites[0].as_ref().unwrap();
+        assert_eq!(a0.codon_pos, 3); assert_eq!(a0.alts[0].consequence, "synonymous_variant");
+        // TAA→TGA: stop retained
+        assert_eq!(an.sites[1].as_ref().unwrap().alts[0].consequence, "stop_retained_variant");
+        // - strand: ATG→GTG at codon position 1 (Met→Val)
+        let a2 = an.sites[2].as_ref().unwrap();
+        assert_eq!(a2.codon_pos, 1); assert_eq!(&a2.alts[0].codon, b"GTG");
+        assert_eq!(a2.alts[0].consequence, "missense_variant");
+        assert_eq!(crate::annotate::csq_field(a2, &v[2], &an.cds), "C|g2|-|1|ATG/GTG|M/V|missense_variant");
+        write_annotation_tsv(&an, &v, ao).unwrap();
+        let c = std::fs::read_to_string(ao).unwrap();
+        assert_eq!(c.lines().nth(1).unwrap(), "6\t6\tA\tG\tg1\t+\t3\tGCA/GCG\tA/A\tsynonymous_variant");
+        // Phase 1: codons start at position 2 (TGG CAT), the last two bases are a partial codon
+        std::fs::write(go, "chr\t.\tCDS\t1\t9\t.\t+\t1\tID=g1\n").unwrap();
+        let an = annotate(&v, read_gff(go, None, sl).unwrap(), &rs);
+        let a0 = an.sites[0].as_ref().unwrap();
+        assert_eq!((a0.codon_pos, &a0.alts[0].codon, a0.alts[0].consequence), (2, b"CGT", "missense_variant"));
+        assert!(an.sites[1].as_ref().unwrap().ref_codon.is_none());
+        // Features on several sequences need --gff-seqid; features past the reference are rejected
+        std::fs::write(go, "chr\t.\tCDS\t1\t9\t.\t+\t0\tID=a\nplasmid\t.\tCDS\t1\t6\t.\t+\t0\tID=b\n").unwrap();
+        assert!(read_gff(go, None, sl).is_err());
+        assert_eq!(read_gff(go, Some("plasmid"), sl).unwrap().iter().map(|c| c.id.as_str()).collect::<Vec<_>>(), vec!["b"]);
+        std::fs::write(go, "chr\t.\tCDS\t10\t16\t.\t-\t0\tID=a\n").unwrap();
+        assert!(read_gff(go, None, sl).is_err());
+        for f in [p.as_str(), go, ao] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_partitions() {
+        // CDS 1-6 (+): columns 1-3 codon 1..3, 4-6 codon 1..3; columns 7-9 non-coding
+        let p = tmp("prtg", ">ref\nATGGCAAAA\n>s1\nCTGGCTAAA\n>s2\nATGGCAAGA\n");
+        let go = "/tmp/snpick_t_prtg.gff"; let o = "/tmp/snpick_t_prtg_out.fa";
+        let qo = "/tmp/snpick_t_prtg.part"; let po = "/tmp/snpick_t_prtg.tsv";
+        std::fs::write(go, "chr\t.\tCDS\t1\t6\t.\t+\t0\tID=g1\n").unwrap();
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        let parts = partition_sites(&bm, &column_partitions(&rs, &read_gff(go, None, sl).unwrap()), &v, None);
+        assert_eq!(parts[0].constant.fconst(), "0,0,1,0");
+        assert_eq!(parts[3].constant.fconst(), "2,0,0,0");
+        let order = partition_order(&parts);
+        assert_eq!(order, vec![0, 1, 2]);
+        let ep = ExtractParams { column_order: Some(&order), ..ExtractParams::new(&recs, o, &lk, &up, layout) };
+        pass2_extract(&mut v, &ep).unwrap();
+        write_partition_file(&parts, "GTR+G", qo).unwrap();
+        assert_eq!(std::fs::read_to_string(qo).unwrap(),
+            "GTR+G+ASC_STAM{0/0/1/0}, codon1 = 1-1\nGTR+G+ASC_STAM{0/0/1/0}, codon3 = 2-2\nGTR+G+ASC_STAM{2/0/0/0}, noncoding = 3-3\n");
+        write_positions(&v, Some(&order), po).unwrap();
+        for f in [p.as_str(), go, o, qo, po] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_column_order() {
+        let p = tmp("cordg", ">s1\nATGC\n>s2\nCTCA\n");
+        let o = "/tmp/snpick_t_cordg_out.fa"; let po = "/tmp/snpick_t_cordg.tsv";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        let order = [2, 0, 1];
+        let ep = ExtractParams { column_order: Some(&order), ..ExtractParams::new(&recs, o, &lk, &up, layout) };
+        pass2_extract(&mut v, &ep).unwrap();
+        assert_eq!(std::fs::read_to_string(o).unwrap(), ">s1\nCAG\n>s2\nACC\n");
+        write_positions(&v, Some(&order), po).unwrap();
+        let c = std::fs::read_to_string(po).unwrap();
+        assert_eq!(c.lines().nth(1).unwrap(), "1\t4\t4\tC\tA");
+        for f in [p.as_str(), o, po] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_site_class() {
+        // CDS 1-12 (+): GCT (Ala, 4-fold) AAA (Lys, 2-fold) CTG (Leu, 4-fold) TAA
+        let p = tmp("scg", ">ref\nGCTAAACTGTAAC\n>s1\nGCCAAGCTATAAT\n>s2\nACTAAACTGTAAC\n");
+        let go = "/tmp/snpick_t_scg.gff";
+        std::fs::write(go, "chr\t.\tCDS\t1\t12\t.\t+\t0\tID=g1\n").unwrap();
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let cds = read_gff(go, None, sl).unwrap();
+        let sel = select_columns(&rs, &cds, SiteClass::Fourfold);
+        let (v, sc) = analyze_selected(&bm, &rs, &lk, false, Some(&sel));
+        assert_eq!(v.iter().map(|x| x.index).collect::<Vec<_>>(), vec![2, 8]);
+        assert_eq!(sc.constant.total(), 0);
+        assert_eq!(sc.excluded, 11);
+        let sel3 = select_columns(&rs, &cds, SiteClass::Codon3);
+        let (v3, sc3) = analyze_selected(&bm, &rs, &lk, false, Some(&sel3));
+        assert_eq!(v3.iter().map(|x| x.index).collect::<Vec<_>>(), vec![2, 5, 8]);
+        assert_eq!(sc3.constant.fconst(), "1,0,0,0");
+        std::fs::remove_file(&p).ok(); std::fs::remove_file(go).ok();
+    }
+
+    #[test] fn test_catalogue() {
+        // The reference gap at column 3 shifts coordinates 3-5 by one column; s3 is missing INH position 2
+        let p = tmp("catg", ">ref\nAC-GTA\n>s1\nACAGTA\n>s2\nACTGAA\n>s3\nAN-GTT\n");
+        let cp = "/tmp/snpick_t_catg.tsv"; let ro = "/tmp/snpick_t_catg_res.tsv";
+        std::fs::write(cp, "position\tref\talt\tdrug\tconfidence\n\
+            2\tC\tG\tINH\thigh\n5\tA\tT\tRIF\thigh\n4\tT\tA\tRIF\tlow\n3\tGA\tG\tRIF\t.\n").unwrap();
+        let m = setup(&p);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut entries, skipped) = read_catalogue(cp).unwrap();
+        assert_eq!((entries.len(), skipped), (3, 1));
+        assert_eq!(resolve_columns(&mut entries, &rs).unwrap(), 0);
+        assert_eq!(entries.iter().map(|e| e.column).collect::<Vec<_>>(), vec![1, 5, 4]);
+        write_resistance_report(&recs, layout, &up, &entries, ro).unwrap();
+        assert_eq!(std::fs::read_to_string(ro).unwrap(), "sample\tdrug\tprediction\tmutations\tmissing\n\
+            ref\tINH\tS\t.\t.\nref\tRIF\tS\t.\t.\n\
+            s1\tINH\tS\t.\t.\ns1\tRIF\tS\t.\t.\n\
+            s2\tINH\tS\t.\t.\ns2\tRIF\tR\t4:T>A(low)\t.\n\
+            s3\tINH\tU\t.\t2\ns3\tRIF\tR\t5:A>T(high)\t.\n");
+        for f in [p.as_str(), cp, ro] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_lineage() {
+        let p = tmp("ling", ">ref\nAAAAAAAA\n>s1\nAAGGTAAA\n>s2\nCCAAATAA\n>s3\nNNNNNNNN\n>s4\nAAGCAAAA\n");
+        let bp = "/tmp/snpick_t_ling.tsv"; let lo = "/tmp/snpick_t_ling_out.tsv";
+        std::fs::write(bp, "position\tallele\tlineage\n1\tC\tlineage1\n2\tC\tlineage1\n\
+            3\tG\tlineage2\n4\tG\tlineage2\n5\tT\tlineage2.1\n6\tT\tlineage4\n").unwrap();
+        let m = setup(&p);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let mut bc = read_barcode(bp).unwrap();
+        assert_eq!(bc.lineages, vec!["lineage1", "lineage2", "lineage2.1", "lineage4"]);
+        lineage::resolve_columns(&mut bc, &rs).unwrap();
+        let sum = write_lineage_report(&recs, layout, &up, &bc, 0.8, lo).unwrap();
+        assert_eq!((sum.unassigned, sum.mixed), (3, 1));
+        assert_eq!(std::fs::read_to_string(lo).unwrap(), "sample\tlineage\tsupport\tsites\tmixed\tsupported\n\
+            ref\t.\t.\t.\tno\t.\n\
+            s1\tlineage2.1\t1.000\t1/1/1\tno\tlineage2:1.000;lineage2.1:1.000\n\
+            s2\tlineage1\t1.000\t2/2/2\tyes\tlineage1:1.000;lineage4:1.000\n\
+            s3\t.\t.\t.\tno\t.\n\
+            s4\t.\t.\t.\tno\t.\n");
+        for f in [p.as_str(), bp, lo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_forced_sites() {
+        let p = tmp("fsg", ">ref\nACGTA\n>s1\nACGTG\n>s2\nACNTA\n");
+        let sp = "/tmp/snpick_t_fsg.txt"; let fo = "/tmp/snpick_t_fsg_out.fa"; let vo = "/tmp/snpick_t_fsg.vcf";
+        std::fs::write(sp, "position\n2\n4\n5\n").unwrap();
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let forced = read_sites(sp, &rs).unwrap();
+        assert_eq!(forced, vec![1, 3, 4]);
+        let (mut v, mut sc) = analyze(&bm, &rs, &lk, false);
+        force_columns(&bm, &rs, &lk, false, None, &forced, &mut v, &mut sc);
+        assert_eq!(v.iter().map(|x| (x.index, x.forced)).collect::<Vec<_>>(), vec![(1, true), (3, true), (4, true)]);
+        assert_eq!((sc.constant.fconst().as_str(), sc.variable, sc.forced), ("1,0,1,0", 1, 2));
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        assert_eq!(std::fs::read_to_string(fo).unwrap(), ">ref\nCTA\n>s1\nCTG\n>s2\nCTA\n");
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions::default()).unwrap();
+        let c = std::fs::read_to_string(vo).unwrap();
+        assert!(c.contains("\n1\t2\t.\tC\t.\t.\tPASS\tNS=3;AN=3;NMISS=0;NGAP=0;FORCED\tGT\t0\t0\t0\n"));
+        // Panel only: the variable column 5 is excluded, the constant panel site kept
+        std::fs::write(sp, "3\n").unwrap();
+        let forced = read_sites(sp, &rs).unwrap();
+        let sel: Vec<bool> = (0..sl).map(|c| forced.contains(&c)).collect();
+        let (mut v, mut sc) = analyze_selected(&bm, &rs, &lk, false, Some(&sel));
+        force_columns(&bm, &rs, &lk, false, Some(&sel), &forced, &mut v, &mut sc);
+        assert_eq!(v.iter().map(|x| (x.index, x.ref_base)).collect::<Vec<_>>(), vec![(2, b'G')]);
+        assert_eq!((sc.constant.total(), sc.excluded, sc.forced), (0, 4, 1));
+        for f in [p.as_str(), sp, fo, vo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_flanks() {
+        let p = tmp("flg", ">ref\nACGT-ACGTA\n>s1\nACTT-ACGTA\n>s2\nACGTTACCTA\n");
+        let fo = "/tmp/snpick_t_flg.fa";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (v, _) = analyze(&bm, &rs, &lk, false);
+        assert_eq!(write_flanks(&v, &rs, 5, &lk, fo).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(fo).unwrap(),
+            ">site1 position=3 ref_position=3 nearby=1\nAC[G/T]TACST\n\
+            >site2 position=8 ref_position=7 nearby=1\nCKTAC[G/C]TA\n");
+        assert_eq!(write_flanks(&v, &rs, 1, &lk, fo).unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(fo).unwrap().lines().nth(1).unwrap(), "C[G/T]T");
+        // A forced constant site is neither counted nor masked in other sites' flanks
+        let (mut v, mut sc) = analyze(&bm, &rs, &lk, false);
+        force_columns(&bm, &rs, &lk, false, None, &[0, 5], &mut v, &mut sc);
+        assert_eq!(write_flanks(&v, &rs, 5, &lk, fo).unwrap(), 4);
+        assert_eq!(std::fs::read_to_string(fo).unwrap(),
+            ">site1 position=1 ref_position=1 nearby=1\n[A]CKTAC\n\
+            >site2 position=3 ref_position=3 nearby=1\nAC[G/T]TACST\n\
+            >site3 position=6 ref_position=5 nearby=2\nACKT[A]CSTA\n\
+            >site4 position=8 ref_position=7 nearby=1\nCKTAC[G/C]TA\n");
+        std::fs::remove_file(&p).ok(); std::fs::remove_file(fo).ok();
+    }
+
+    #[test] fn test_lowercase_as_missing() {
+        let p = tmp("lcg", ">s1\nACGTA\n>s2\nAcGTa\n>s3\nACtTA\n");
+        let fo = "/tmp/snpick_t_lcg_out.fa";
+        let m = setup(&p);
+        let mut lk = build_lookup(false);
+        let mut up = build_upper();
+        mask_lowercase(&mut lk, &mut up);
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        assert_eq!(pass1_scan_masked(&recs, sl, layout, &lk).1, vec![0, 2, 1]);
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, sc) = analyze(&bm, &rs, &lk, false);
+        assert_eq!(v.len(), 0);
+        assert_eq!(sc.constant.fconst(), "2,1,1,1");
+        // Lowercase calls at a truly variable column become N
+        let p2 = tmp("lcg2", ">s1\nACGTA\n>s2\nAcGTG\n>s3\nACtTa\n");
+        let m2 = setup(&p2);
+        let (recs, sl, layout) = index_fasta(&m2).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        (v, _) = analyze(&bm, &rs, &lk, false);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        assert_eq!(std::fs::read_to_string(fo).unwrap(), ">s1\nA\n>s2\nG\n>s3\nN\n");
+        assert_eq!((g, v[0].ns), (b"AGN".to_vec(), 2));
+        // A masked reference base is not REF; the first observed allele is, as for N
+        let p3 = tmp("lcg3", ">ref\nAcGT\n>s1\nAGGT\n>s2\nATGT\n");
+        let m3 = setup(&p3);
+        let (recs, sl, layout) = index_fasta(&m3).unwrap();
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (v, _) = analyze(&pass1_scan(&recs, sl, layout, &lk), &rs, &lk, false);
+        assert_eq!((v[0].ref_base, v[0].alt_bases.as_slice()), (b'G', b"T".as_slice()));
+        for f in [p.as_str(), p2.as_str(), p3.as_str(), fo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_site_categories() {
+        let p = tmp("catsg", ">s1\nA-NRAC\n>s2\nA-NN-C\n>s3\nA-NYAG\n");
+        let m = setup(&p);
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        for (gaps, var, constant, gapped) in [(false, 1, 2, 1), (true, 2, 1, 0)] {
+            let lk = build_lookup(gaps);
+            let bm = pass1_scan(&recs, sl, layout, &lk);
+            let rs = get_ref_seq(&recs[0], sl, layout);
+            let (_, sc) = analyze(&bm, &rs, &lk, gaps);
+            assert_eq!((sc.variable, sc.constant.total(), sc.gapped_constant), (var, constant, gapped));
+            assert_eq!((sc.gap_only, sc.missing, sc.ambiguous), (1, 1, 1));
+            assert_eq!(sc.variable + sc.constant.total() + sc.gap_only + sc.missing + sc.ambiguous, sl);
+        }
+        std::fs::remove_file(&p).ok();
+    }
+
+    #[test] fn test_vcf_indels() {
+        let p = tmp("indg", ">ref\nACGTTA-CA\n>s1\nACG--A-CA\n>s2\nACGT-A-CA\n>s3\nACGTTAGCA\n>s4\nACNTTA-CT\n");
+        let fo = "/tmp/snpick_t_indg_out.fa"; let vo = "/tmp/snpick_t_indg.vcf";
+        let m = setup(&p);
+        let lk = build_lookup(true);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, true);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        let ic = call_indels(&g, recs.len(), &v, &rs);
+        assert_eq!(ic.unanchored, 0);
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions { indels: Some(&ic.records), ..Default::default() }).unwrap();
+        let c = std::fs::read_to_string(vo).unwrap();
+        let body: Vec<&str> = c.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(body, vec![
+            "1\t3\t.\tGT\tG\t.\tPASS\tNS=4;AC=1;AN=4;AF=0.25;INDEL\tGT\t0\t.\t1\t0\t0",
+            "1\t3\t.\tGTT\tG\t.\tPASS\tNS=4;AC=1;AN=4;AF=0.25;INDEL\tGT\t0\t1\t.\t0\t0",
+            "1\t6\t.\tA\tAG\t.\tPASS\tNS=5;AC=1;AN=5;AF=0.2;INDEL\tGT\t0\t0\t0\t1\t0",
+            "1\t9\t.\tA\tT\t.\tPASS\tNS=5;AC=1;AN=5;AF=0.2;NMISS=0;NGAP=0\tGT\t0\t0\t0\t0\t1",
+        ]);
+        for f in [p.as_str(), fo, vo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_vcf_indels_ref_gap() {
+        // s1 deletes G and T across the reference gap column: REF CGT would need columns 2..=5, so it is skipped
+        let p = tmp("indrg", ">ref\nACG-TA\n>s1\nAC---A\n>s2\nACGTTA\n");
+        let fo = "/tmp/snpick_t_indrg_out.fa";
+        let m = setup(&p);
+        let lk = build_lookup(true);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, true);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        let ic = call_indels(&g, recs.len(), &v, &rs);
+        assert_eq!((ic.unanchored, ic.ref_gap), (0, 1));
+        // s2's insertion after G is anchored at column 3, where its REF lies
+        assert_eq!(ic.records.len(), 1);
+        let r = &ic.records[0];
+        assert_eq!((r.column, r.ref_allele.as_slice(), r.alt_allele.as_slice()), (2, &b"G"[..], &b"GT"[..]));
+        assert_eq!(r.genotypes, vec![Some(0), Some(0), Some(1)]);
+        for f in [p.as_str(), fo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_vcf_indels_unanchored() {
+        // The only gap run starts at the reference start: no indel records, and no `*` alleles either;
+        // the gap call at column 1 is a missing GT and is not counted in NS
+        let p = tmp("indug", ">ref\nACGTA\n>s1\n--GTA\n>s2\nTCGTT\n");
+        let fo = "/tmp/snpick_t_indug_out.fa"; let vo = "/tmp/snpick_t_indug.vcf";
+        let m = setup(&p);
+        let lk = build_lookup(true);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, true);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        let ic = call_indels(&g, recs.len(), &v, &rs);
+        assert_eq!((ic.records.len(), ic.unanchored, ic.ref_gap), (0, 1, 0));
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions { indels: Some(&ic.records), ..Default::default() }).unwrap();
+        let c = std::fs::read_to_string(vo).unwrap();
+        let body: Vec<&str> = c.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(body, vec![
+            "1\t1\t.\tA\tT\t.\tPASS\tNS=2;AC=1;AN=2;AF=0.5;NMISS=0;NGAP=1\tGT\t0\t.\t1",
+            "1\t5\t.\tA\tT\t.\tPASS\tNS=3;AC=1;AN=3;AF=0.3333;NMISS=0;NGAP=0\tGT\t0\t0\t1",
+        ]);
+        for f in [p.as_str(), fo, vo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_merge_mnps() {
+        let p = tmp("mnpg", ">ref\nACGTAC\n>s1\nAGCTAC\n>s2\nAGCTAC\n>s3\nACGTTC\n>s4\nANGTAC\n>s5\nATCTAC\n");
+        let fo = "/tmp/snpick_t_mnpg_out.fa"; let vo = "/tmp/snpick_t_mnpg.vcf";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        assert_eq!(crate::vcf::mnp_groups(&g, recs.len(), &v, |_| false), vec![(0, 1), (2, 2)]);
+        // Annotated and forced sites keep their own records
+        assert_eq!(crate::vcf::mnp_groups(&g, recs.len(), &v, |vi| vi == 1), vec![(0, 0), (1, 1), (2, 2)]);
+        v[0].forced = true;
+        assert_eq!(crate::vcf::mnp_groups(&g, recs.len(), &v, |_| false), vec![(0, 0), (1, 1), (2, 2)]);
+        v[0].forced = false;
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions { merge_mnps: true, ..Default::default() }).unwrap();
+        let c = std::fs::read_to_string(vo).unwrap();
+        let body: Vec<&str> = c.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(body, vec![
+            "1\t2\t.\tCG\tGC,TC\t.\tPASS\tNS=5;AC=2,1;AN=5;AF=0.4,0.2;NMISS=1;NGAP=0;MNP\tGT\t0\t1\t1\t0\t.\t2",
+            "1\t5\t.\tA\tT\t.\tPASS\tNS=6;AC=1;AN=6;AF=0.1667;NMISS=0;NGAP=0\tGT\t0\t0\t0\t1\t0\t0",
+        ]);
+        for f in [p.as_str(), fo, vo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_multiallelic() {
+        let p = tmp("mag", ">ref\nACGT\n>s1\nACGA\n>s2\nACGC\n>s3\nGCGT\n>s4\nNCGA\n");
+        let fo = "/tmp/snpick_t_mag_out.fa"; let vo = "/tmp/snpick_t_mag.vcf";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, mut sc) = analyze(&bm, &rs, &lk, false);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        write_vcf(&g, recs.len(), &v, vo, &recs, sl, &VcfOptions { split_multiallelic: true, ..Default::default() }).unwrap();
+        let c = std::fs::read_to_string(vo).unwrap();
+        let body: Vec<&str> = c.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(body, vec![
+            "1\t1\t.\tA\tG\t.\tPASS\tNS=4;AC=1;AN=4;AF=0.25;NMISS=1;NGAP=0\tGT\t0\t0\t0\t1\t.",
+            "1\t4\t.\tT\tA\t.\tPASS\tNS=5;AC=2;AN=5;AF=0.4;NMISS=0;NGAP=0\tGT\t0\t1\t0\t0\t1",
+            "1\t4\t.\tT\tC\t.\tPASS\tNS=5;AC=1;AN=5;AF=0.2;NMISS=0;NGAP=0\tGT\t0\t0\t1\t0\t0",
+        ]);
+        drop_multiallelic(&mut v, &mut sc);
+        assert_eq!(v.iter().map(|x| x.index).collect::<Vec<_>>(), vec![0]);
+        assert_eq!((sc.variable, sc.multiallelic), (1, 1));
+        for f in [p.as_str(), fo, vo] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_sites_only() {
+        let p = tmp("sog", ">ref\nACGT\n>s1\nACGA\n>s2\nAC-C\n>s3\nGCGT\n>s4\nNCGA\n");
+        let fo = "/tmp/snpick_t_sog_out.fa"; let vo = "/tmp/snpick_t_sog.vcf"; let to = "/tmp/snpick_t_sog.tsv";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        let ep = ExtractParams { collect_counts: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        assert!(pass2_extract(&mut v, &ep).unwrap().vcf_geno.is_none());
+        write_vcf(&[], recs.len(), &v, vo, &recs, sl, &VcfOptions { sites_only: true, ..Default::default() }).unwrap();
+        let c = std::fs::read_to_string(vo).unwrap();
+        assert!(!c.contains("FORMAT") && c.contains("\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n"));
+        let body: Vec<&str> = c.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(body, vec![
+            "1\t1\t.\tA\tG\t.\tPASS\tNS=4;AC=1;AN=4;AF=0.25;NMISS=1;NGAP=0",
+            "1\t4\t.\tT\tA,C\t.\tPASS\tNS=5;AC=2,1;AN=5;AF=0.4,0.2;NMISS=0;NGAP=0",
+        ]);
+        write_variant_table(&v, to).unwrap();
+        assert_eq!(std::fs::read_to_string(to).unwrap(),
+            "position\tref_position\tref\talt\tref_count\talt_count\tmissing\tgap\n\
+            1\t1\tA\tG\t3\t1\t1\t0\n4\t4\tT\tA,C\t2\t2,1\t0\t0\n");
+        for f in [p.as_str(), fo, vo, to] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_streamed_vcf() {
+        let p = tmp("stg", ">ref\nACGTACGT\n>s1\nAGCTACGA\n>s2\nAGCTTCGA\n>s3\nTCGTACGT\n");
+        let fo = "/tmp/snpick_t_stg_out.fa"; let vo = "/tmp/snpick_t_stg.vcf"; let so = "/tmp/snpick_t_stg_s.vcf";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        assert_eq!(v.iter().map(|x| x.index).collect::<Vec<_>>(), vec![0, 1, 2, 4, 7]);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let g = pass2_extract(&mut v, &ep).unwrap().vcf_geno.unwrap();
+        let cols: Vec<usize> = v.iter().map(|x| x.index).collect();
+        let joined = |e: usize| mnp_pair(&g[(e - 1) * 4..e * 4], &g[e * 4..(e + 1) * 4], &v[e - 1], &v[e]);
+        // 2 sites per block; columns 1 and 2 co-vary and stay together, 0 and 1 do not
+        assert_eq!(geno_blocks(&v, 4, 8, |_| Ok(false)).unwrap(), vec![0..2, 2..4, 4..5]);
+        assert_eq!(geno_blocks(&v, 4, 8, |e| Ok(joined(e))).unwrap(), vec![0..1, 1..3, 3..5]);
+        // A co-varying pair cannot fit a one-site budget
+        assert!(geno_blocks(&v, 4, 4, |e| Ok(joined(e))).is_err());
+        for merge_mnps in [false, true] {
+            let opts = VcfOptions { merge_mnps, ..Default::default() };
+            write_vcf(&g, recs.len(), &v, vo, &recs, sl, &opts).unwrap();
+            let mut vw = VcfWriter::create(so, &recs, sl, &v, &opts).unwrap();
+            let mut bg = Vec::new();
+            for b in geno_blocks(&v, recs.len(), 8, |e| Ok(merge_mnps && joined(e))).unwrap() {
+                fetch_block(&recs, &cols[b.clone()], layout, &up, 8, &mut bg).unwrap();
+                vw.write_sites(&bg, b).unwrap();
+            }
+            vw.finish().unwrap();
+            assert_eq!(std::fs::read_to_string(vo).unwrap(), std::fs::read_to_string(so).unwrap());
+        }
+        for f in [p.as_str(), fo, vo, so] { std::fs::remove_file(f).ok(); }
+    }
+
+    #[test] fn test_parallel_pass2() {
+        // Enough records for several threads; multi-line to exercise the scanning reader
+        let mut fa = String::new();
+        for i in 0..64u32 {
+            let seq: String = (0..40u32).map(|j| b"ACGTN"[((i * 7 + j * 13) * (j % 3) % 5) as usize] as char).collect();
+            fa.push_str(&format!(">s{}\n{}\n{}\n", i, &seq[..25], &seq[25..]));
+        }
+        let p = tmp("ppg", &fa);
+        let fo = "/tmp/snpick_t_ppg_out.fa";
+        let m = setup(&p);
+        let lk = build_lookup(false);
+        let up = build_upper();
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let rs = get_ref_seq(&recs[0], sl, layout);
+        let (mut v, _) = analyze(&bm, &rs, &lk, false);
+        let ep = ExtractParams { collect_vcf: true, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let g = pool.install(|| pass2_extract(&mut v, &ep)).unwrap().vcf_geno.unwrap();
+        assert!(!layout.single_line && v.len() > 10);
+        let cols: Vec<usize> = v.iter().map(|x| x.index).collect();
+        let mut expected = String::new();
+        let mut row = vec![0u8; cols.len()];
+        for (si, rec) in recs.iter().enumerate() {
+            fetch_columns(rec, &cols, layout, &up, &mut row);
+            expected.push_str(&format!(">s{}\n{}\n", si, String::from_utf8_lossy(&row)));
+            for (vi, &b) in row.iter().enumerate() { assert_eq!(g[vi * recs.len() + si], b); }
+        }
+        assert_eq!(std::fs::read_to_string(fo).unwrap(), expected);
+        for (vi, vp) in v.iter().enumerate() {
+            assert_eq!(vp.ns, (0..recs.len()).filter(|&si| g[vi * recs.len() + si] != b'N').count());
+        }
+        // Rounds of 3 rows (not a multiple of the thread count) and of a single row
+        for round_bytes in [3 * cols.len() + 1, 1] {
+            let ep = ExtractParams { collect_vcf: true, round_bytes, ..ExtractParams::new(&recs, fo, &lk, &up, layout) };
+            assert_eq!(pool.install(|| pass2_extract(&mut v, &ep)).unwrap().vcf_geno.unwrap(), g);
+            assert_eq!(std::fs::read_to_string(fo).unwrap(), expected);
+            let mut bg = Vec::new();
+            pool.install(|| fetch_block(&recs, &cols[2..7], layout, &up, round_bytes, &mut bg)).unwrap();
+            assert_eq!(bg, g[2 * recs.len()..7 * recs.len()]);
+        }
+        std::fs::remove_file(&p).ok(); std::fs::remove_file(fo).ok();
+    }
+
+    #[test] fn test_simd_scan() {
+        use crate::simd::{Classifier, Kernel};
+        // Mostly common bytes, with IUPAC codes, lowercase and '?' sprinkled in
+        let seq: Vec<u8> = (0..1000u32).map(|i| match i % 97 { 13 => b'R', 50 => b'?', 71 => b'y', _ => b"ACGTNacgt-."[(i * 31 % 11) as usize] }).collect();
+        for gaps in [false, true] {
+            let mut lk = build_lookup(gaps);
+            let mut up = build_upper();
+            if gaps { mask_lowercase(&mut lk, &mut up); }
+            let table = build_scan_table(&lk);
+            let mut expected = vec![0u8; 1000];
+            Classifier::with_kernel(&table, Kernel::Scalar).or_into(&seq, &mut expected);
+            for kernel in [Kernel::Sse2, Kernel::Avx2] {
+                for off in [0, 1, 33] {
+                    let mut bm = vec![0u8; 1000];
+                    bm[..off].copy_from_slice(&expected[..off]);
+                    Classifier::with_kernel(&table, kernel).or_into(&seq[off..], &mut bm[off..]);
+                    assert_eq!(bm, expected, "{:?} offset {}", kernel, off);
+                }
+            }
+        }
+    }
+
+    /// Pass 1 kernel timings on a simulated 4.4 Mbp alignment
+    /// (`cargo test --release bench_simd_scan -- --ignored --nocapture`).
+    #[test] #[ignore] fn bench_simd_scan() {
+        use crate::simd::{Classifier, Kernel};
+        const LEN: usize = 4_400_000;
+        let nseq = 50;
+        // Reference plus ~1 SNP per 1000 bp, a few gaps and Ns, as in the simulated datasets
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut rand = move || { state ^= state << 13; state ^= state >> 7; state ^= state << 17; state };
+        let reference: Vec<u8> = (0..LEN).map(|_| b"ACGT"[(rand() % 4) as usize]).collect();
+        let seqs: Vec<Vec<u8>> = (0..nseq).map(|_| {
+            let mut s = reference.clone();
+            for _ in 0..LEN / 1000 { let i = (rand() as usize) % LEN; s[i] = b"ACGT"[(rand() % 4) as usize]; }
+            for _ in 0..LEN / 10000 { let i = (rand() as usize) % LEN; s[i] = if rand() % 2 == 0 { b'-' } else { b'N' }; }
+            s
+        }).collect();
+        let table = build_scan_table(&build_lookup(false));
+        let mut expected = None;
+        for kernel in [Kernel::Scalar, Kernel::Sse2, Kernel::Avx2] {
+            let c = Classifier::with_kernel(&table, kernel);
+            let mut bm = vec![0u8; LEN];
+            let t = Instant::now();
+            for s in &seqs { c.or_into(s, &mut bm); }
+            let secs = t.elapsed().as_secs_f64();
+            eprintln!("{:?}\t{}\t{}\t{:.3}\t{:.2}", kernel, nseq, LEN, secs, (nseq * LEN) as f64 / secs / 1e9);
+            assert_eq!(*expected.get_or_insert_with(|| bm.clone()), bm);
+        }
+    }
+
+    #[test] fn test_fixed_width() {
+        let cases: [(&[u8], bool); 6] = [
+            (b">a\nACGTA\nCGtAC\nGT\n>b\nACG\nTAC\nGTa\nCGT\n", true),
+            (b">a\r\nACGTA\r\nCGtAC\r\nGT\r\n>b\r\nACGTACGtACGT\r\n", true),
+            (b">a\nACGTA\nCGT\nACGT\n>b\nACGTACGtACGT\n", false),
+            (b">a\nACGTA\n\nCGtAC\nGT\n>b\nACGTACGTACGT\n", false),
+            (b">a\nACGTA\r\nCGtAC\nGT\n>b\nACGTACGTACGT\n", false),
+            (b">a\nACGTA\nCGTA\r\nCGT\n>b\nACGTACGTACGT\n>c\nACGTACGTACGT\n", false),
+        ];
+        let lk = build_lookup(false);
+        let up = build_upper();
+        for (i, (fa, fixed)) in cases.iter().enumerate() {
+            let p = tmp(&format!("fwg{}", i), "");
+            std::fs::write(&p, fa).unwrap();
+            let m = setup(&p);
+            let (recs, sl, layout) = index_fasta(&m).unwrap();
+            assert_eq!((sl, layout.single_line, layout.fixed_width), (12, false, *fixed), "case {}", i);
+            // The fixed-width paths agree with the scanning ones
+            let scanning = SeqLayout { single_line: false, fixed_width: false };
+            let cols: Vec<usize> = (0..sl).rev().step_by(3).rev().collect();
+            for rec in &recs {
+                assert_eq!(get_ref_seq(rec, sl, layout), get_ref_seq(rec, sl, scanning));
+                let (mut a, mut b) = (vec![0u8; cols.len()], vec![0u8; cols.len()]);
+                fetch_columns(rec, &cols, layout, &up, &mut a);
+                fetch_columns(rec, &cols, scanning, &up, &mut b);
+                assert_eq!(a, b);
+            }
+            assert_eq!(pass1_scan(&recs, sl, layout, &lk), pass1_scan(&recs, sl, scanning, &lk));
+            assert_eq!(pass1_scan_masked(&recs, sl, layout, &lk), pass1_scan_masked(&recs, sl, scanning, &lk));
+            std::fs::remove_file(&p).ok();
+        }
+    }
+
+    #[test] fn test_fai() {
+        let p = tmp("faig", ">a first\nACGTA\nCGTAC\nGT\n>b\nACG\nTAC\nGTA\nCGT\n");
+        let fi = "/tmp/snpick_t_faig.fai";
+        let m = setup(&p);
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        write_fai(&recs, sl, layout, fi).unwrap();
+        assert_eq!(std::fs::read_to_string(fi).unwrap(), "a\t12\t9\t5\t6\nb\t12\t27\t3\t4\n");
+        let (frecs, fsl, flayout) = read_fai(&m, fi).unwrap();
+        assert_eq!((fsl, flayout.single_line, flayout.fixed_width), (12, false, true));
+        for (a, b) in recs.iter().zip(frecs.iter()) {
+            assert_eq!((a.id, a.desc, a.seq_offset, a.line_width, a.line_stride),
+                (b.id, b.desc, b.seq_offset, b.line_width, b.line_stride));
+        }
+        // Unequal lengths, renamed or shifted records are rejected from the index
+        for bad in ["a\t12\t9\t5\t6\nb\t11\t27\t3\t4\n", "a\t12\t9\t5\t6\nc\t12\t27\t3\t4\n", "a\t12\t9\t5\t6\nb\t12\t27\t4\t5\n"] {
+            std::fs::write(fi, bad).unwrap();
+            assert!(read_fai(&m, fi).is_err(), "{}", bad);
+        }
+        // An index missing a record, or written before a record was appended, is stale
+        std::fs::write(fi, "b\t12\t27\t3\t4\n").unwrap();
+        assert!(read_fai(&m, fi).is_err());
+        write_fai(&recs, sl, layout, fi).unwrap();
+        let p2 = tmp("faig2", ">a first\nACGTA\nCGTAC\nGT\n>b\nACG\nTAC\nGTA\nCGT\n>c\nACGTACGTACGT\n\n");
+        assert!(read_fai(&setup(&p2), fi).is_err());
+        std::fs::write(&p2, ">a first\nACGTA\nCGTAC\nGT\n>b\nACG\nTAC\nGTA\nCGT\n\r\n").unwrap();
+        assert_eq!(read_fai(&setup(&p2), fi).unwrap().0.len(), 2);
+        let irregular = SeqLayout { single_line: false, fixed_width: false };
+        assert!(write_fai(&recs, sl, irregular, fi).is_err());
+        std::fs::remove_file(&p).ok(); std::fs::remove_file(&p2).ok(); std::fs::remove_file(fi).ok();
+    }
+
+    #[test] fn test_cache_append() {
+        let p = tmp("cag", ">a\nACGT\n>b\nACGA");
+        let cp = "/tmp/snpick_t_cag.cache"; let np = tmp("cag_new", ">c\nTCGA\n");
+        let lk = build_lookup(false);
+        let m = setup(&p);
+        let (recs, sl, layout) = index_fasta(&m).unwrap();
+        let bm = pass1_scan(&recs, sl, layout, &lk);
+        let loc = |source: usize, r: &FastaRecord| RecordLoc {
+            source, seq_offset: r.seq_offset, line_width: r.line_width, line_stride: r.line_stride, masked: 0,
+        };
+        let cache = Pass1Cache {
+            include_gaps: false, lowercase_as_missing: false, seq_length: sl, layout,
+            sources: vec![SourceFile::stat(&p).unwrap()],
+            records: recs.iter().map(|r| loc(0, r)).collect(),
+            bitmask: bm.clone(),
+        };
+        write_cache(&cache, cp).unwrap();
+        let c = read_cache(cp).unwrap();
+        let src = SourceFile::stat(&p).unwrap();
+        assert!(c.matches(&src, false, false) && !c.matches(&src, true, false));
+        assert_eq!(c.bitmask, bm);
+        assert_eq!(c.records(&[&m]).unwrap().iter().map(|r| r.id).collect::<Vec<_>>(), vec![b"a", b"b"]);
+        // Wrong length and duplicate IDs are rejected; the appended file is indexed where it lies
+        assert!(index_appended(b">c\nTCG\n", "x", sl, &recs).is_err());
+        assert!(index_appended(b">b\nTCGA\n", "x", sl, &recs).is_err());
+        let mn = setup(&np);
+        let (new, nl) = index_appended(&mn, &np, sl, &recs).unwrap();
+        assert_eq!(new.len(), 1);
+        // Scanning only the appended records gives the full bitmask
+        let mut merged = c.bitmask.clone();
+        for (a, b) in merged.iter_mut().zip(pass1_scan(&new, sl, nl, &lk)) { *a |= b; }
+        let all: Vec<FastaRecord> = recs.iter().chain(new.iter())
+            .map(|r| FastaRecord { ..*r }).collect();
+        assert_eq!(merged, pass1_scan(&all, sl, layout, &lk));
+        let cache = Pass1Cache {
+            sources: vec![src.clone(), SourceFile::stat(&np).unwrap()],
+            records: recs.iter().map(|r| loc(0, r)).chain(new.iter().map(|r| loc(1, r))).collect(),
+            bitmask: merged,
+            ..c
+        };
+        write_cache(&cache, cp).unwrap();
+        let c = read_cache(cp).unwrap();
+        assert!(c.matches(&src, false, false));
+        assert_eq!(c.records(&[&m, &mn]).unwrap().iter().map(|r| r.id).collect::<Vec<_>>(), vec![b"a", b"b", b"c"]);
+        // The input is left as it was; a same-size edit of any source invalidates the cache
+        assert_eq!(std::fs::read_to_string(&p).unwrap(), ">a\nACGT\n>b\nACGA");
+        std::fs::write(&np, ">c\nTCGT\n").unwrap();
+        assert!(!c.matches(&src, false, false));
+        std::fs::write(&p, ">a\nACGT\n>b\nACGG").unwrap();
+        assert!(!c.matches(&SourceFile::stat(&p).unwrap(), false, false));
+        // ... also when it restores the modification time, through the content digest
+        let before = SourceFile::stat(&p).unwrap();
+        let mtime = std::fs::metadata(&p).unwrap().modified().unwrap();
+        std::fs::write(&p, ">a\nACGT\n>b\nACGC").unwrap();
+        std::fs::File::options().write(true).open(&p).unwrap().set_modified(mtime).unwrap();
+        let after = SourceFile::stat(&p).unwrap();
+        assert_eq!((after.len, after.mtime, after.inode), (before.len, before.mtime, before.inode));
+        assert!(!before.unchanged(&after));
+        // A truncated or foreign cache is an error, which main turns into a rescan
+        let bytes = std::fs::read(cp).unwrap();
+        std::fs::write(cp, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(read_cache(cp).is_err());
+        std::fs::write(cp, b"SNPICKC1").unwrap();
+        assert!(read_cache(cp).is_err());
+        for f in [p.as_str(), cp, np.as_str()] { std::fs::remove_file(f).ok(); }
+    }
+
     #[test] fn test_empty() {
         let p = tmp("empg", "");
         let m = setup(&p);
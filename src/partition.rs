@@ -0,0 +1,140 @@
+//! Codon-position partitioning and site-class selection of alignment columns.
+//!
+//! Assigns every alignment column to codon position 1, 2 or 3 of an annotated
+//! CDS, or to the non-coding set, and computes per-partition constant-site
+//! counts so that ASC corrections can be applied per partition. Columns can also
+//! be restricted to third-codon or 4-fold degenerate sites.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::annotate::{codon_at, complement, find_cds, translate, CdsFeature};
+use crate::types::*;
+
+/// Partition names, indexed by partition ID.
+pub const PARTITION_NAMES: [&str; 4] = ["codon1", "codon2", "codon3", "noncoding"];
+
+/// Partition ID of non-coding columns (including reference-gap columns).
+pub const NONCODING: u8 = 3;
+
+/// Variable columns and constant-site counts of one partition.
+pub struct Partition {
+    pub name: &'static str,
+    pub constant: ConstantSiteCounts,
+    /// Indices into the variable positions, ascending.
+    pub columns: Vec<usize>,
+}
+
+/// Partition ID of every alignment column, from the reference (first) sequence.
+///
+/// Columns in several overlapping CDSs take the codon position of the first one.
+pub fn column_partitions(ref_seq: &[u8], cds: &[CdsFeature]) -> Vec<u8> {
+    let max_len = cds.iter().map(|c| c.end - c.start).max().unwrap_or(0);
+    let mut parts = vec![NONCODING; ref_seq.len()];
+    let mut rp = 0usize;
+    for (part, &b) in parts.iter_mut().zip(ref_seq.iter()) {
+        if is_gap(b) { continue; }
+        if let Some(ci) = find_cds(cds, max_len, rp) {
+            *part = codon_at(&cds[ci], rp).0 as u8;
+        }
+        rp += 1;
+    }
+    parts
+}
+
+/// Coding site classes that columns can be restricted to.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SiteClass {
+    /// Third codon positions.
+    Codon3,
+    /// 4-fold degenerate third positions (per the reference codon, table 11).
+    Fourfold,
+}
+
+/// Whether the codon family starting with `b1 b2` (coding orientation) is 4-fold degenerate.
+fn is_fourfold(b1: u8, b2: u8) -> bool {
+    let aa = translate(&[b1, b2, b'A']);
+    aa != b'X' && [b'C', b'G', b'T'].iter().all(|&b3| translate(&[b1, b2, b3]) == aa)
+}
+
+/// Mark alignment columns belonging to `class`, from the reference (first) sequence.
+///
+/// Degeneracy is judged on the reference codon; columns where the reference has a
+/// gap, or whose codon is incomplete, are never selected.
+pub fn select_columns(ref_seq: &[u8], cds: &[CdsFeature], class: SiteClass) -> Vec<bool> {
+    let ref_ungapped: Vec<u8> = ref_seq.iter().copied().filter(|&b| !is_gap(b)).collect();
+    let max_len = cds.iter().map(|c| c.end - c.start).max().unwrap_or(0);
+    let mut select = vec![false; ref_seq.len()];
+    let mut rp = 0usize;
+    for (sel, &b) in select.iter_mut().zip(ref_seq.iter()) {
+        if is_gap(b) { continue; }
+        if let Some(ci) = find_cds(cds, max_len, rp) {
+            let c = &cds[ci];
+            match codon_at(c, rp) {
+                (2, Some(g)) if g[0].max(g[2]) < ref_ungapped.len() => {
+                    *sel = match class {
+                        SiteClass::Codon3 => true,
+                        SiteClass::Fourfold => {
+                            let orient = |p: usize| if c.strand == b'+' { ref_ungapped[p].to_ascii_uppercase() }
+                                else { complement(ref_ungapped[p]) };
+                            is_fourfold(orient(g[0]), orient(g[1]))
+                        }
+                    };
+                }
+                _ => {}
+            }
+        }
+        rp += 1;
+    }
+    select
+}
+
+/// Split variable positions and constant sites by partition.
+///
+/// With `select`, constant counts only include selected columns (variable
+/// positions are assumed to be selected already). Constant columns that are part
+/// of the output (forced sites) are not counted.
+pub fn partition_sites(
+    bitmask: &[u8], parts: &[u8], var_positions: &[VariablePosition], select: Option<&[bool]>,
+) -> Vec<Partition> {
+    let mut out: Vec<Partition> = PARTITION_NAMES.iter()
+        .map(|&name| Partition { name, constant: ConstantSiteCounts::default(), columns: Vec::new() })
+        .collect();
+    let mut vi = 0;
+    for (pos, (&bits, &p)) in bitmask.iter().zip(parts.iter()).enumerate() {
+        while vi < var_positions.len() && var_positions[vi].index < pos { vi += 1; }
+        if vi < var_positions.len() && var_positions[vi].index == pos { continue; }
+        if select.is_some_and(|sel| !sel[pos]) { continue; }
+        if site_category(bits) == SiteCategory::Constant { out[p as usize].constant.add(bits); }
+    }
+    for (vi, vp) in var_positions.iter().enumerate() {
+        out[parts[vp.index] as usize].columns.push(vi);
+    }
+    out
+}
+
+/// Output column order: variable positions grouped by partition.
+pub fn partition_order(partitions: &[Partition]) -> Vec<usize> {
+    partitions.iter().flat_map(|p| p.columns.iter().copied()).collect()
+}
+
+/// Write a RAxML-NG style partition file over the partition-ordered alignment.
+///
+/// Each non-empty partition gets `<model>+ASC_STAM{A/C/G/T}, <name> = <start>-<end>`
+/// with its own constant-site counts. Empty partitions are omitted.
+pub fn write_partition_file(partitions: &[Partition], model: &str, path: &str) -> io::Result<()> {
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create partition file '{}': {}", path, e)))?;
+    let mut w = BufWriter::new(out);
+
+    let mut start = 1;
+    for p in partitions.iter().filter(|p| !p.columns.is_empty()) {
+        let c = &p.constant;
+        let end = start + p.columns.len() - 1;
+        writeln!(w, "{}+ASC_STAM{{{}/{}/{}/{}}}, {} = {}-{}",
+            model, c.a, c.c, c.g, c.t, p.name, start, end)?;
+        start = end + 1;
+    }
+
+    w.flush()
+}
@@ -0,0 +1,99 @@
+//! Site-pattern compression.
+//!
+//! Collapses identical variable columns of the genotype matrix into unique
+//! patterns with multiplicity weights, for likelihood tools that accept
+//! weighted alignments (e.g. RAxML-NG `--site-weights`).
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::extract::write_record;
+use crate::fasta::FastaRecord;
+use crate::types::{VariablePosition, IO_BUF};
+
+/// Unique site patterns over a set of rows (records).
+pub struct SitePatterns {
+    /// Column index (into variable positions) of the first occurrence of each pattern.
+    pub first_column: Vec<usize>,
+    /// Number of columns sharing each pattern.
+    pub weights: Vec<usize>,
+    /// Variable position of every output column.
+    pub columns: Vec<usize>,
+    /// Pattern index of every output column.
+    pub column_pattern: Vec<usize>,
+}
+
+/// Group columns of the site-major genotype matrix by their pattern over `rows`.
+///
+/// Columns are taken in `column_order` (indices into the variable positions,
+/// default: as given), the order of the main output, and patterns are numbered
+/// in order of first appearance.
+pub fn compress_patterns(
+    vcf_geno: &[u8], num_samples: usize, num_var: usize, rows: &[usize], column_order: Option<&[usize]>,
+) -> SitePatterns {
+    let mut index: HashMap<Vec<u8>, usize> = HashMap::new();
+    let columns = column_order.map_or_else(|| (0..num_var).collect(), <[usize]>::to_vec);
+    let mut sp = SitePatterns {
+        first_column: Vec::new(), weights: Vec::new(), columns: Vec::new(), column_pattern: Vec::with_capacity(num_var),
+    };
+    let mut col = vec![0u8; rows.len()];
+
+    for &vi in &columns {
+        let site = &vcf_geno[vi * num_samples..(vi + 1) * num_samples];
+        for (c, &r) in col.iter_mut().zip(rows.iter()) { *c = site[r]; }
+        let pi = match index.get(col.as_slice()) {
+            Some(&pi) => pi,
+            None => {
+                let pi = sp.weights.len();
+                index.insert(col.clone(), pi);
+                sp.first_column.push(vi);
+                sp.weights.push(0);
+                pi
+            }
+        };
+        sp.weights[pi] += 1;
+        sp.column_pattern.push(pi);
+    }
+
+    sp.columns = columns;
+    sp
+}
+
+/// Write the compressed alignment, the weights file and the column → pattern map.
+///
+/// The weights file holds one integer per pattern, in alignment column order.
+/// The map has columns: output column, alignment position (1-based), pattern (1-based).
+#[allow(clippy::too_many_arguments)]
+pub fn write_patterns(
+    sp: &SitePatterns, vcf_geno: &[u8], num_samples: usize, rows: &[usize],
+    records: &[FastaRecord], var_positions: &[VariablePosition],
+    fasta_path: &str, weights_path: &str, map_path: &str,
+) -> io::Result<()> {
+    let out = File::create(fasta_path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create pattern alignment '{}': {}", fasta_path, e)))?;
+    let mut w = BufWriter::with_capacity(IO_BUF, out);
+    let mut row = vec![0u8; sp.weights.len()];
+    for &r in rows {
+        for (b, &vi) in row.iter_mut().zip(sp.first_column.iter()) {
+            *b = vcf_geno[vi * num_samples + r];
+        }
+        write_record(&mut w, &records[r], &row)?;
+    }
+    w.flush()?;
+
+    let out = File::create(weights_path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create weights file '{}': {}", weights_path, e)))?;
+    let mut w = BufWriter::new(out);
+    for wt in &sp.weights { writeln!(w, "{}", wt)?; }
+    w.flush()?;
+
+    let out = File::create(map_path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create pattern map '{}': {}", map_path, e)))?;
+    let mut w = BufWriter::new(out);
+    writeln!(w, "column\tposition\tpattern")?;
+    for (oc, (&pi, &vi)) in sp.column_pattern.iter().zip(sp.columns.iter()).enumerate() {
+        writeln!(w, "{}\t{}\t{}", oc + 1, var_positions[vi].index + 1, pi + 1)?;
+    }
+    w.flush()
+}
@@ -0,0 +1,120 @@
+//! Column position map for the reduced alignment.
+//!
+//! Records, for every output column, the alignment position it was taken from,
+//! the ungapped reference coordinate, and the REF/ALT alleles. The same map is
+//! read back by `snpick expand`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::types::{is_gap, VariablePosition};
+
+/// Write the column → position map as TSV.
+///
+/// Columns: output column, alignment position, reference position (`.` if the
+/// reference has a gap), REF, ALT (comma-separated, `.` if none). All 1-based.
+/// `order` gives the output column order when it differs from `var_positions`.
+pub fn write_positions(
+    var_positions: &[VariablePosition], order: Option<&[usize]>, path: &str,
+) -> io::Result<()> {
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create position map '{}': {}", path, e)))?;
+    let mut w = BufWriter::new(out);
+
+    writeln!(w, "column\tposition\tref_position\tref\talt")?;
+    let identity: Vec<usize>;
+    let order = match order {
+        Some(o) => o,
+        None => { identity = (0..var_positions.len()).collect(); &identity }
+    };
+    for (ci, vp) in order.iter().map(|&vi| &var_positions[vi]).enumerate() {
+        write!(w, "{}\t{}\t", ci + 1, vp.index + 1)?;
+        match vp.ref_pos {
+            Some(rp) => write!(w, "{}", rp + 1)?,
+            None => write!(w, ".")?,
+        }
+        write!(w, "\t{}\t", vp.ref_base as char)?;
+        if vp.alt_bases.is_empty() {
+            w.write_all(b".")?;
+        }
+        for (i, &ab) in vp.alt_bases.iter().enumerate() {
+            if i > 0 { w.write_all(b",")?; }
+            w.write_all(&[ab])?;
+        }
+        writeln!(w)?;
+    }
+
+    w.flush()
+}
+
+/// A column entry read back from a position map.
+pub struct ColumnPosition {
+    /// 0-based alignment position.
+    pub index: usize,
+    /// 0-based ungapped reference coordinate, `None` if the reference has a gap.
+    pub ref_pos: Option<usize>,
+}
+
+/// Read a position map written by `write_positions`, in output column order.
+pub fn read_positions(path: &str) -> io::Result<Vec<ColumnPosition>> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open position map '{}': {}", path, e)))?;
+    let bad = |ln: usize, msg: &str| io::Error::new(io::ErrorKind::InvalidData,
+        format!("Position map '{}' line {}: {}", path, ln, msg));
+
+    let mut cols = Vec::new();
+    for (li, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if li == 0 || line.trim().is_empty() { continue; }
+        let f: Vec<&str> = line.split('\t').collect();
+        if f.len() < 3 { return Err(bad(li + 1, "expected at least 3 columns")); }
+        let index: usize = f[1].parse().map_err(|_| bad(li + 1, "invalid position"))?;
+        if index == 0 { return Err(bad(li + 1, "positions are 1-based")); }
+        let ref_pos = match f[2] {
+            "." => None,
+            v => {
+                let rp: usize = v.parse().map_err(|_| bad(li + 1, "invalid reference position"))?;
+                if rp == 0 { return Err(bad(li + 1, "positions are 1-based")); }
+                Some(rp - 1)
+            }
+        };
+        cols.push(ColumnPosition { index: index - 1, ref_pos });
+    }
+    Ok(cols)
+}
+
+/// Alignment column of every ungapped reference coordinate.
+pub fn ref_columns(ref_seq: &[u8]) -> Vec<usize> {
+    ref_seq.iter().enumerate().filter(|&(_, &b)| !is_gap(b)).map(|(i, _)| i).collect()
+}
+
+/// Read a site panel: one 1-based reference coordinate per line (first column).
+///
+/// A header line and `#` comments are skipped. Returns the matching alignment
+/// columns, ascending and without duplicates.
+pub fn read_sites(path: &str, ref_seq: &[u8]) -> io::Result<Vec<usize>> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot open site list '{}': {}", path, e)))?;
+    let bad = |ln: usize, msg: String| io::Error::new(io::ErrorKind::InvalidData,
+        format!("Site list '{}' line {}: {}", path, ln, msg));
+
+    let cols = ref_columns(ref_seq);
+    let mut sites = Vec::new();
+    for (li, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() { continue; }
+        let field = line.split(['\t', ' ', ',']).next().unwrap_or("").trim();
+        let pos: usize = match field.parse() {
+            Ok(p) => p,
+            Err(_) if li == 0 => continue,
+            Err(_) => return Err(bad(li + 1, format!("invalid position '{}'", field))),
+        };
+        if pos == 0 { return Err(bad(li + 1, "positions are 1-based".into())); }
+        let col = *cols.get(pos - 1).ok_or_else(|| bad(li + 1,
+            format!("position {} lies beyond the reference length {}", pos, cols.len())))?;
+        sites.push(col);
+    }
+    sites.sort_unstable();
+    sites.dedup();
+    Ok(sites)
+}
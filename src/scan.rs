@@ -1,11 +1,13 @@
 //! Pass 1: bitmask scan and site classification.
 //!
 //! Builds a per-position bitmask by OR-ing each sequence's nucleotide flags,
-//! then classifies positions as variable (>1 allele), constant, or ambiguous.
+//! then classifies positions as variable (>1 allele), constant, or one of the
+//! allele-free categories (gap-only, all-missing, ambiguous).
 
 use rayon::prelude::*;
 
 use crate::fasta::FastaRecord;
+use crate::simd::Classifier;
 use crate::types::*;
 
 /// Prefault mmap pages by touching one byte per OS page.
@@ -22,19 +24,41 @@ fn prefault(data: &[u8]) {
     std::hint::black_box(sum);
 }
 
+/// Bytes classified between soft-mask counts, so that counting reads them from cache.
+const COUNT_PIECE: usize = 64 * 1024;
+
 /// Pass 1: build bitmask of observed nucleotides at each position.
 ///
-/// Iterates all sequences, OR-ing each base's lookup value into the bitmask.
-/// Prefaults mmap pages first, then scans sequentially per sequence.
-/// For multi-line FASTA, scans byte-by-byte skipping newlines.
+/// Iterates all sequences, OR-ing each base's lookup value into the bitmask,
+/// together with `SEEN_*` markers for gaps, N and IUPAC codes (see `build_scan_table`).
+/// Prefaults mmap pages first, then scans sequentially per sequence with the
+/// widest SIMD kernel available (see `simd`). Wrapped FASTA is scanned line by
+/// line, at known offsets when the line width is fixed.
 pub fn pass1_scan(
-    data: &[u8], records: &[FastaRecord], seq_length: usize,
-    layout: SeqLayout, lookup: &[u8; 256],
+    records: &[FastaRecord], seq_length: usize, layout: SeqLayout, lookup: &[u8; 256],
 ) -> Vec<u8> {
+    scan(records, seq_length, layout, lookup, false).0
+}
+
+/// As `pass1_scan`, also counting the lowercase `a`/`c`/`g`/`t` calls of each
+/// record in the same pass (for `--lowercase-as-missing`).
+pub fn pass1_scan_masked(
+    records: &[FastaRecord], seq_length: usize, layout: SeqLayout, lookup: &[u8; 256],
+) -> (Vec<u8>, Vec<usize>) {
+    scan(records, seq_length, layout, lookup, true)
+}
+
+fn scan(
+    records: &[FastaRecord], seq_length: usize, layout: SeqLayout, lookup: &[u8; 256], count_masked: bool,
+) -> (Vec<u8>, Vec<usize>) {
     let mut bitmask = vec![0u8; seq_length];
+    let mut masked = vec![0usize; records.len()];
+    let classifier = Classifier::new(&build_scan_table(lookup));
 
-    // Prefault all pages into RAM before the hot loop
-    prefault(data);
+    // Prefault all pages of the mapped files into RAM before the hot loop
+    for (i, rec) in records.iter().enumerate() {
+        if i == 0 || !std::ptr::eq(rec.data, records[i - 1].data) { prefault(rec.data); }
+    }
 
     // Parallel: each thread scans a chunk of sequences into its own bitmask,
     // then merge all partial bitmasks with OR. Threads share the mmap read-only.
@@ -45,15 +69,17 @@ pub fn pass1_scan(
     let total_work = records.len() * seq_length;
     if num_threads <= 1 || total_work < 200_000_000 {
         // Sequential fallback for small inputs
-        scan_sequential(data, records, seq_length, layout, lookup, &mut bitmask);
+        scan_sequential(records, seq_length, layout, &classifier, &mut bitmask, count_masked.then_some(&mut masked));
     } else {
         // Split records into chunks, one per thread
         let chunk_size = records.len().div_ceil(num_threads);
         let partial_bitmasks: Vec<Vec<u8>> = records
             .par_chunks(chunk_size)
-            .map(|chunk| {
+            .zip(masked.par_chunks_mut(chunk_size))
+            .map(|(chunk, chunk_masked)| {
                 let mut local_bm = vec![0u8; seq_length];
-                scan_sequential(data, chunk, seq_length, layout, lookup, &mut local_bm);
+                scan_sequential(chunk, seq_length, layout, &classifier, &mut local_bm,
+                    count_masked.then_some(chunk_masked));
                 local_bm
             })
             .collect();
@@ -66,63 +92,137 @@ pub fn pass1_scan(
         }
     }
 
-    bitmask
+    (bitmask, masked)
 }
 
-/// Sequential scan of a set of records into a bitmask.
+/// Classify `seq` into `bitmask`, adding its lowercase calls to `masked` if given.
+#[inline]
+fn classify(classifier: &Classifier, seq: &[u8], bitmask: &mut [u8], masked: Option<&mut usize>) {
+    match masked {
+        None => classifier.or_into(seq, bitmask),
+        Some(n) => for (piece, bm) in seq.chunks(COUNT_PIECE).zip(bitmask.chunks_mut(COUNT_PIECE)) {
+            classifier.or_into(piece, bm);
+            *n += piece.iter().filter(|&&b| matches!(b, b'a' | b'c' | b'g' | b't')).count();
+        },
+    }
+}
+
+/// Sequential scan of a set of records into a bitmask, with per-record
+/// soft-mask counts if `masked` is given.
 fn scan_sequential(
-    data: &[u8], records: &[FastaRecord], seq_length: usize,
-    layout: SeqLayout, lookup: &[u8; 256], bitmask: &mut [u8],
+    records: &[FastaRecord], seq_length: usize,
+    layout: SeqLayout, classifier: &Classifier, bitmask: &mut [u8], mut masked: Option<&mut [usize]>,
 ) {
-    if layout.single_line {
-        for rec in records {
-            let seq = &data[rec.seq_offset..rec.seq_offset + seq_length];
-            for (bm_byte, &seq_byte) in bitmask.iter_mut().zip(seq.iter()) {
-                *bm_byte |= lookup[seq_byte as usize];
+    for (ri, rec) in records.iter().enumerate() {
+        let data = rec.data;
+        let mut n = masked.as_deref_mut().map(|m| &mut m[ri]);
+        if layout.single_line {
+            classify(classifier, &data[rec.seq_offset..rec.seq_offset + seq_length], bitmask, n);
+        } else if layout.fixed_width {
+            for (k, line) in rec.lines(seq_length).enumerate() {
+                classify(classifier, line, &mut bitmask[k * rec.line_width..], n.as_deref_mut());
             }
-        }
-    } else {
-        for rec in records {
+        } else {
             let mut pos = rec.seq_offset;
             let end = data.len();
             let mut i = 0;
             while i < seq_length && pos < end {
-                let b = data[pos];
-                pos += 1;
-                if b == b'\n' || b == b'\r' { continue; }
-                bitmask[i] |= lookup[b as usize];
-                i += 1;
+                // Classify one line segment, then skip its line break
+                let rest = &data[pos..];
+                let len = rest.iter().position(|&b| b == b'\n' || b == b'\r').unwrap_or(rest.len());
+                let take = len.min(seq_length - i);
+                classify(classifier, &rest[..take], &mut bitmask[i..i + take], n.as_deref_mut());
+                i += take;
+                pos += len + 1;
             }
         }
     }
 }
 
-/// Classify positions into variable, constant, or ambiguous-only.
+/// Classify positions into variable, constant, gap-only, all-missing or ambiguous-only.
 pub fn analyze(
     bitmask: &[u8], ref_seq: &[u8], lookup: &[u8; 256], include_gaps: bool,
+) -> (Vec<VariablePosition>, SiteCounts) {
+    analyze_selected(bitmask, ref_seq, lookup, include_gaps, None)
+}
+
+/// Like `analyze`, restricted to columns where `select` is true.
+/// Other columns are neither variable nor counted as constant (`SiteCounts::excluded`).
+pub fn analyze_selected(
+    bitmask: &[u8], ref_seq: &[u8], lookup: &[u8; 256], include_gaps: bool,
+    select: Option<&[bool]>,
 ) -> (Vec<VariablePosition>, SiteCounts) {
     let mut vars = Vec::new();
-    let mut cs = ConstantSiteCounts { a: 0, c: 0, g: 0, t: 0 };
-    let mut ambiguous = 0usize;
+    let mut counts = SiteCounts::default();
+    let mut ref_coord = 0usize;
 
     for (pos, &bits) in bitmask.iter().enumerate() {
-        let ones = bits.count_ones();
-        if ones > 1 {
-            let rb = ref_seq[pos].to_ascii_uppercase();
-            let ref_base = if lookup[rb as usize] != 0 { rb } else { bits_to_bases(bits, include_gaps)[0] };
+        let ref_gap = is_gap(ref_seq[pos]);
+        let ref_pos = if ref_gap { None } else { Some(ref_coord) };
+        if !ref_gap { ref_coord += 1; }
+        if select.is_some_and(|sel| !sel[pos]) {
+            counts.excluded += 1;
+            continue;
+        }
+
+        if site_category(bits) == SiteCategory::Variable {
+            // A reference base that is not an allele (N, IUPAC, masked) falls back to the first observed one;
+            // alleles are spelled as `bits_to_bases` does (uppercase, `.` as `-`)
+            let rb = lookup[ref_seq[pos] as usize];
+            let ref_base = bits_to_bases(if rb != 0 { rb } else { bits }, include_gaps)[0];
             let alt_bases: Vec<u8> = bits_to_bases(bits, include_gaps)
                 .into_iter().filter(|&b| b != ref_base).collect();
-            vars.push(VariablePosition { index: pos, ref_base, alt_bases, ns: 0 });
-        } else if ones == 1 {
-            if bits & BIT_A != 0 { cs.a += 1; }
-            else if bits & BIT_C != 0 { cs.c += 1; }
-            else if bits & BIT_G != 0 { cs.g += 1; }
-            else if bits & BIT_T != 0 { cs.t += 1; }
+            vars.push(VariablePosition { index: pos, ref_pos, ref_base, alt_bases, ns: 0, calls: CallCounts::default(), forced: false });
         } else {
-            ambiguous += 1;
+            counts.add(bits);
+        }
+    }
+
+    counts.variable = vars.len();
+    (vars, counts)
+}
+
+/// Add the `forced` columns (ascending) to the output regardless of variability.
+///
+/// Columns that are already variable are only marked as forced. Others are added
+/// with their observed alleles and removed from their site category or the
+/// excluded count, so that each column is counted once for ASC corrections.
+#[allow(clippy::too_many_arguments)]
+pub fn force_columns(
+    bitmask: &[u8], ref_seq: &[u8], lookup: &[u8; 256], include_gaps: bool,
+    select: Option<&[bool]>, forced: &[usize],
+    vars: &mut Vec<VariablePosition>, counts: &mut SiteCounts,
+) {
+    let mut ref_coord = 0usize;
+    let mut col = 0usize;
+    let num_var = vars.len();
+    for &c in forced {
+        while col < c {
+            if !is_gap(ref_seq[col]) { ref_coord += 1; }
+            col += 1;
         }
+        if let Ok(vi) = vars[..num_var].binary_search_by_key(&c, |v| v.index) {
+            vars[vi].forced = true;
+            continue;
+        }
+        let bits = bitmask[c];
+        if select.is_some_and(|sel| !sel[c]) { counts.excluded -= 1; } else { counts.remove(bits); }
+
+        let rb = lookup[ref_seq[c] as usize];
+        let observed = bits_to_bases(bits, include_gaps);
+        let ref_base = if rb != 0 { bits_to_bases(rb, include_gaps)[0] } else { observed.first().copied().unwrap_or(b'N') };
+        let ref_pos = if is_gap(ref_seq[c]) { None } else { Some(ref_coord) };
+        let alt_bases = observed.into_iter().filter(|&b| b != ref_base).collect();
+        vars.push(VariablePosition { index: c, ref_pos, ref_base, alt_bases, ns: 0, calls: CallCounts::default(), forced: true });
     }
+    counts.forced = vars.len() - num_var;
+    vars.sort_unstable_by_key(|v| v.index);
+}
 
-    let num_variable = vars.len();
-    (vars, SiteCounts { constant: cs, variable: num_variable, ambiguous })
+/// Drop variable positions with more than two alleles (forced sites are kept).
+pub fn drop_multiallelic(vars: &mut Vec<VariablePosition>, counts: &mut SiteCounts) {
+    let before = vars.len();
+    vars.retain(|v| v.forced || v.alt_bases.len() <= 1);
+    counts.multiallelic = before - vars.len();
+    counts.variable -= counts.multiallelic;
 }
@@ -0,0 +1,166 @@
+//! Vectorized pass 1 classification.
+//!
+//! ORs the scan-table classes of a sequence slice into the bitmask 32 (AVX2) or
+//! 16 (SSE2) bytes at a time. Each block is compared against the bytes that make
+//! up nearly all of an alignment (ACGTN in both cases, `-`, `.`); a block holding
+//! any other byte falls back to the table lookup, so every path produces exactly
+//! the scalar result. The instruction set is detected at runtime and can be
+//! overridden with `SNPICK_SIMD=scalar|sse2|avx2` (for benchmarking).
+
+/// Bytes classified by comparison; all others go through the table. Blocks of
+/// only the first four (the usual case) need a single round of comparisons.
+const COMMON: [u8; 12] = *b"ACGTNacgtn-.";
+
+/// Classification kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel { Scalar, Sse2, Avx2 }
+
+impl Kernel {
+    /// The widest kernel supported by this CPU.
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if std::arch::is_x86_feature_detected!("avx2") { return Kernel::Avx2; }
+            Kernel::Sse2
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        { Kernel::Scalar }
+    }
+
+    /// The kernel requested by `SNPICK_SIMD`, if set to a known name.
+    pub fn from_env() -> Option<Self> {
+        match std::env::var("SNPICK_SIMD").ok()?.to_ascii_lowercase().as_str() {
+            "scalar" => Some(Kernel::Scalar),
+            "sse2" => Some(Kernel::Sse2),
+            "avx2" => Some(Kernel::Avx2),
+            _ => None,
+        }
+    }
+
+    /// Whether this CPU can run the kernel.
+    pub fn supported(self) -> bool {
+        match self {
+            Kernel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => true,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => std::arch::is_x86_feature_detected!("avx2"),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+}
+
+/// A scan table paired with the kernel that applies it.
+pub struct Classifier {
+    table: [u8; 256],
+    kernel: Kernel,
+}
+
+impl Classifier {
+    /// Use the kernel requested by `SNPICK_SIMD`, else the widest available.
+    pub fn new(table: &[u8; 256]) -> Self {
+        Self::with_kernel(table, Kernel::from_env().unwrap_or_else(Kernel::detect))
+    }
+
+    /// Use `kernel`, or the scalar one if this CPU cannot run it.
+    pub fn with_kernel(table: &[u8; 256], kernel: Kernel) -> Self {
+        Classifier { table: *table, kernel: if kernel.supported() { kernel } else { Kernel::Scalar } }
+    }
+
+    /// `bitmask[i] |= table[seq[i]]` over the common length of both slices.
+    #[inline]
+    pub fn or_into(&self, seq: &[u8], bitmask: &mut [u8]) {
+        let n = seq.len().min(bitmask.len());
+        let (seq, bitmask) = (&seq[..n], &mut bitmask[..n]);
+        match self.kernel {
+            Kernel::Scalar => or_scalar(seq, &self.table, bitmask),
+            // SAFETY: `with_kernel` and `detect` only select kernels the CPU supports
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => unsafe { x86::or_sse2(seq, &self.table, bitmask) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { x86::or_avx2(seq, &self.table, bitmask) },
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => or_scalar(seq, &self.table, bitmask),
+        }
+    }
+}
+
+#[inline]
+fn or_scalar(seq: &[u8], table: &[u8; 256], bitmask: &mut [u8]) {
+    for (bm, &b) in bitmask.iter_mut().zip(seq.iter()) {
+        *bm |= table[b as usize];
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::{or_scalar, COMMON};
+
+    /// Per-byte classes of one block, and whether every byte was a common one.
+    macro_rules! classify {
+        ($x:expr, $pats:expr, $zero:expr, $cmpeq:ident, $and:ident, $or:ident) => {{
+            let (mut vals, mut hit) = ($zero, $zero);
+            for &(p, v) in $pats.iter() {
+                let eq = $cmpeq($x, p);
+                vals = $or(vals, $and(eq, v));
+                hit = $or(hit, eq);
+            }
+            (vals, hit)
+        }};
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) fn or_avx2(seq: &[u8], table: &[u8; 256], bitmask: &mut [u8]) {
+        let pats = COMMON.map(|b| (_mm256_set1_epi8(b as i8), _mm256_set1_epi8(table[b as usize] as i8)));
+        let n = seq.len();
+        let mut i = 0;
+        while i + 32 <= n {
+            // SAFETY: `i + 32 <= n` and both slices have length `n`
+            let x = unsafe { _mm256_loadu_si256(seq.as_ptr().add(i) as *const __m256i) };
+            let (mut vals, mut hit) = classify!(x, pats[..4], _mm256_setzero_si256(),
+                _mm256_cmpeq_epi8, _mm256_and_si256, _mm256_or_si256);
+            if _mm256_movemask_epi8(hit) != -1 {
+                let (v, h) = classify!(x, pats[4..], _mm256_setzero_si256(),
+                    _mm256_cmpeq_epi8, _mm256_and_si256, _mm256_or_si256);
+                (vals, hit) = (_mm256_or_si256(vals, v), _mm256_or_si256(hit, h));
+            }
+            if _mm256_movemask_epi8(hit) == -1 {
+                let dst = unsafe { bitmask.as_mut_ptr().add(i) as *mut __m256i };
+                unsafe { _mm256_storeu_si256(dst, _mm256_or_si256(_mm256_loadu_si256(dst), vals)) };
+            } else {
+                or_scalar(&seq[i..i + 32], table, &mut bitmask[i..i + 32]);
+            }
+            i += 32;
+        }
+        or_scalar(&seq[i..], table, &mut bitmask[i..]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) fn or_sse2(seq: &[u8], table: &[u8; 256], bitmask: &mut [u8]) {
+        let pats = COMMON.map(|b| (_mm_set1_epi8(b as i8), _mm_set1_epi8(table[b as usize] as i8)));
+        let n = seq.len();
+        let mut i = 0;
+        while i + 16 <= n {
+            // SAFETY: `i + 16 <= n` and both slices have length `n`
+            let x = unsafe { _mm_loadu_si128(seq.as_ptr().add(i) as *const __m128i) };
+            let (mut vals, mut hit) = classify!(x, pats[..4], _mm_setzero_si128(),
+                _mm_cmpeq_epi8, _mm_and_si128, _mm_or_si128);
+            if _mm_movemask_epi8(hit) != 0xFFFF {
+                let (v, h) = classify!(x, pats[4..], _mm_setzero_si128(),
+                    _mm_cmpeq_epi8, _mm_and_si128, _mm_or_si128);
+                (vals, hit) = (_mm_or_si128(vals, v), _mm_or_si128(hit, h));
+            }
+            if _mm_movemask_epi8(hit) == 0xFFFF {
+                let dst = unsafe { bitmask.as_mut_ptr().add(i) as *mut __m128i };
+                unsafe { _mm_storeu_si128(dst, _mm_or_si128(_mm_loadu_si128(dst), vals)) };
+            } else {
+                or_scalar(&seq[i..i + 16], table, &mut bitmask[i..i + 16]);
+            }
+            i += 16;
+        }
+        or_scalar(&seq[i..], table, &mut bitmask[i..]);
+    }
+}
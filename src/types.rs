@@ -10,10 +10,19 @@ pub const BIT_G: u8 = 0b00100;
 pub const BIT_T: u8 = 0b01000;
 pub const BIT_GAP: u8 = 0b10000;
 
+/// Allele bits of a pass 1 bitmask entry.
+pub const ALLELE_BITS: u8 = 0b11111;
+
+/// Pass 1 markers for characters that are not alleles, kept above the allele
+/// bits so that columns without any allele can still be categorised.
+pub const SEEN_GAP: u8 = 0b0010_0000;
+pub const SEEN_N: u8 = 0b0100_0000;
+pub const SEEN_AMBIG: u8 = 0b1000_0000;
+
 /// Maximum alignment length (prevents OOM on malicious input).
 pub const MAX_SEQ_LENGTH: usize = 10_000_000_000;
 
-/// Maximum VCF genotype matrix size in bytes.
+/// Default in-memory genotype matrix budget in bytes (`--max-memory`).
 pub const MAX_VCF_GENO_BYTES: usize = 4_000_000_000;
 
 /// I/O buffer size for BufWriter (16 MB).
@@ -21,21 +30,67 @@ pub const IO_BUF: usize = 16 * 1024 * 1024;
 
 /// Whether all sequences are single-line (no embedded newlines).
 /// When true, `data[seq_offset + pos]` gives the base at `pos` directly.
-/// When false, we must scan skipping newlines for each record.
+/// When false but `fixed_width`, every record is wrapped at a fixed line width
+/// (as `.fai` requires) and `FastaRecord::offset_of` gives the byte of `pos`.
+/// Otherwise we must scan skipping newlines for each record.
 #[derive(Clone, Copy)]
 pub struct SeqLayout {
     pub single_line: bool,
+    pub fixed_width: bool,
 }
 
 /// A variable position detected in the alignment.
 pub struct VariablePosition {
     pub index: usize,
+    /// 0-based ungapped coordinate in the reference (first) sequence, `None` if the
+    /// reference has a gap at this column.
+    pub ref_pos: Option<usize>,
     pub ref_base: u8,
     pub alt_bases: Vec<u8>,
     pub ns: usize,
+    /// Calls by base, collected in pass 2 together with the genotype matrix.
+    pub calls: CallCounts,
+    /// Included because it was requested with `--sites`, not (only) because it varies.
+    pub forced: bool,
+}
+
+/// Per-site call counts: A/C/G/T, gaps and missing (N, IUPAC) calls.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct CallCounts {
+    pub bases: [usize; 4],
+    pub gap: usize,
+    pub missing: usize,
+}
+
+impl CallCounts {
+    /// Count one (uppercased) call.
+    #[inline]
+    pub fn add(&mut self, b: u8) {
+        match b {
+            b'A' => self.bases[0] += 1,
+            b'C' => self.bases[1] += 1,
+            b'G' => self.bases[2] += 1,
+            b'T' => self.bases[3] += 1,
+            _ if is_gap(b) => self.gap += 1,
+            _ => self.missing += 1,
+        }
+    }
+
+    /// Number of calls of `allele` (a base or `-`); 0 for anything else.
+    pub fn of(&self, allele: u8) -> usize {
+        match allele {
+            b'A' => self.bases[0],
+            b'C' => self.bases[1],
+            b'G' => self.bases[2],
+            b'T' => self.bases[3],
+            b'-' => self.gap,
+            _ => 0,
+        }
+    }
 }
 
 /// Counts of constant sites by nucleotide.
+#[derive(Default)]
 pub struct ConstantSiteCounts {
     pub a: usize,
     pub c: usize,
@@ -50,15 +105,102 @@ impl std::fmt::Display for ConstantSiteCounts {
 }
 
 impl ConstantSiteCounts {
+    /// Count a single-allele position by its base.
+    pub fn add(&mut self, bits: u8) {
+        if bits & BIT_A != 0 { self.a += 1; }
+        else if bits & BIT_C != 0 { self.c += 1; }
+        else if bits & BIT_G != 0 { self.g += 1; }
+        else if bits & BIT_T != 0 { self.t += 1; }
+    }
+    /// Undo `add` for a position that is moved into the output.
+    pub fn remove(&mut self, bits: u8) {
+        if bits & BIT_A != 0 { self.a -= 1; }
+        else if bits & BIT_C != 0 { self.c -= 1; }
+        else if bits & BIT_G != 0 { self.g -= 1; }
+        else if bits & BIT_T != 0 { self.t -= 1; }
+    }
     pub fn total(&self) -> usize { self.a + self.c + self.g + self.t }
     pub fn fconst(&self) -> String { format!("{},{},{},{}", self.a, self.c, self.g, self.t) }
 }
 
+/// Category of an alignment column, from its pass 1 bitmask entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SiteCategory {
+    /// Two or more alleles.
+    Variable,
+    /// A single A/C/G/T allele (other rows may be gaps or missing).
+    Constant,
+    /// Gaps only (a constant gap with `-g`).
+    GapOnly,
+    /// No allele, only N (or soft-masked bases) and gaps.
+    Missing,
+    /// No allele, but IUPAC ambiguity codes.
+    Ambiguous,
+}
+
+/// Categorise a pass 1 bitmask entry.
+pub fn site_category(bits: u8) -> SiteCategory {
+    let alleles = bits & ALLELE_BITS;
+    match alleles.count_ones() {
+        0 if bits & SEEN_AMBIG != 0 => SiteCategory::Ambiguous,
+        0 if bits & SEEN_N != 0 => SiteCategory::Missing,
+        0 if bits & SEEN_GAP != 0 => SiteCategory::GapOnly,
+        0 => SiteCategory::Missing,
+        1 if alleles == BIT_GAP => SiteCategory::GapOnly,
+        1 => SiteCategory::Constant,
+        _ => SiteCategory::Variable,
+    }
+}
+
 /// Summary of site classification across the alignment.
+///
+/// `variable + forced + multiallelic + constant.total() + gap_only + missing + ambiguous
+/// + excluded` equals the alignment length.
+#[derive(Default)]
 pub struct SiteCounts {
     pub constant: ConstantSiteCounts,
+    /// Constant positions that also contain gaps (excluded without `-g`).
+    pub gapped_constant: usize,
     pub variable: usize,
+    pub gap_only: usize,
+    pub missing: usize,
     pub ambiguous: usize,
+    /// Positions outside the selected site class or site panel (see `--site-class`, `--sites-only`).
+    pub excluded: usize,
+    /// Non-variable positions included through `--sites`.
+    pub forced: usize,
+    /// Variable positions with more than two alleles dropped by `--biallelic-only`.
+    pub multiallelic: usize,
+}
+
+impl SiteCounts {
+    /// Count a non-variable position in its category.
+    pub fn add(&mut self, bits: u8) {
+        match site_category(bits) {
+            SiteCategory::Constant => {
+                self.constant.add(bits);
+                if bits & SEEN_GAP != 0 { self.gapped_constant += 1; }
+            }
+            SiteCategory::GapOnly => self.gap_only += 1,
+            SiteCategory::Missing => self.missing += 1,
+            SiteCategory::Ambiguous => self.ambiguous += 1,
+            SiteCategory::Variable => self.variable += 1,
+        }
+    }
+
+    /// Undo `add` for a position that is moved into the output.
+    pub fn remove(&mut self, bits: u8) {
+        match site_category(bits) {
+            SiteCategory::Constant => {
+                self.constant.remove(bits);
+                if bits & SEEN_GAP != 0 { self.gapped_constant -= 1; }
+            }
+            SiteCategory::GapOnly => self.gap_only -= 1,
+            SiteCategory::Missing => self.missing -= 1,
+            SiteCategory::Ambiguous => self.ambiguous -= 1,
+            SiteCategory::Variable => self.variable -= 1,
+        }
+    }
 }
 
 /// Build nucleotide → bitmask lookup table.
@@ -68,7 +210,22 @@ pub fn build_lookup(include_gaps: bool) -> [u8; 256] {
     t[b'C' as usize] = BIT_C; t[b'c' as usize] = BIT_C;
     t[b'G' as usize] = BIT_G; t[b'g' as usize] = BIT_G;
     t[b'T' as usize] = BIT_T; t[b't' as usize] = BIT_T;
-    if include_gaps { t[b'-' as usize] = BIT_GAP; }
+    if include_gaps { t[b'-' as usize] = BIT_GAP; t[b'.' as usize] = BIT_GAP; }
+    t
+}
+
+/// Build the pass 1 scan table: `lookup` for alleles, `SEEN_*` markers for gaps,
+/// N (including bases masked out of `lookup`) and other IUPAC codes.
+pub fn build_scan_table(lookup: &[u8; 256]) -> [u8; 256] {
+    let mut t = [0u8; 256];
+    for (i, v) in t.iter_mut().enumerate() {
+        let b = i as u8;
+        *v = if lookup[i] != 0 { lookup[i] }
+            else if is_gap(b) { SEEN_GAP }
+            else if b == b'\n' || b == b'\r' { 0 }
+            else if matches!(b.to_ascii_uppercase(), b'N' | b'?' | b'A' | b'C' | b'G' | b'T') { SEEN_N }
+            else { SEEN_AMBIG };
+    }
     t
 }
 
@@ -80,6 +237,19 @@ pub fn build_upper() -> [u8; 256] {
     t
 }
 
+/// Treat lowercase (soft-masked) bases as missing: they set no bits in `lookup`
+/// and are written as `N` through `upper`.
+pub fn mask_lowercase(lookup: &mut [u8; 256], upper: &mut [u8; 256]) {
+    for c in b'a'..=b'z' {
+        lookup[c as usize] = 0;
+        upper[c as usize] = b'N';
+    }
+}
+
+/// Whether a byte is an alignment gap (`-` or `.`).
+#[inline]
+pub fn is_gap(b: u8) -> bool { b == b'-' || b == b'.' }
+
 /// Convert bitmask to sorted list of bases it represents.
 pub fn bits_to_bases(bits: u8, include_gaps: bool) -> Vec<u8> {
     let mut v = Vec::with_capacity(5);
@@ -90,3 +260,9 @@ pub fn bits_to_bases(bits: u8, include_gaps: bool) -> Vec<u8> {
     if include_gaps && bits & BIT_GAP != 0 { v.push(b'-'); }
     v
 }
+
+/// IUPAC nucleotide code for a set of A/C/G/T bits (`N` for none).
+pub fn bits_to_iupac(bits: u8) -> u8 {
+    const CODES: &[u8; 16] = b"NACMGRSVTWYHKDBN";
+    CODES[(bits & (BIT_A | BIT_C | BIT_G | BIT_T)) as usize]
+}
@@ -1,63 +1,343 @@
 //! VCF output writer.
 //!
-//! Generates VCF v4.2 from the genotype matrix built during pass 2.
+//! Generates VCF v4.2 from the genotype matrix built during pass 2, or block by
+//! block from per-block matrices when the full matrix would not fit in memory.
 //! Uses a per-position lookup table for O(1) allele → index mapping.
 
 use std::fs::File;
 use std::io::{self, BufWriter, Write};
+use std::ops::Range;
 
+use crate::annotate::{csq_field, Annotations};
 use crate::fasta::FastaRecord;
-use crate::types::VariablePosition;
+use crate::indel::IndelRecord;
+use crate::types::{is_gap, VariablePosition};
+
+/// Optional VCF content.
+#[derive(Default)]
+pub struct VcfOptions<'a> {
+    /// Sites within a CDS carry a `CSQ` INFO field.
+    pub annotations: Option<&'a Annotations>,
+    /// Represent gaps by these indel records instead of `*` alleles: gap alleles
+    /// are dropped from the per-column records (gap calls become `.`), and columns
+    /// where the reference has a gap or no base allele remains are omitted.
+    pub indels: Option<&'a [IndelRecord]>,
+    /// Merge adjacent co-varying sites into MNP records (see `mnp_groups`).
+    pub merge_mnps: bool,
+    /// Write one biallelic row per ALT allele, as `bcftools norm -m-`.
+    pub split_multiallelic: bool,
+    /// Omit FORMAT and sample columns; `vcf_geno` is not read (and may be empty).
+    /// Incompatible with `indels` and `merge_mnps`, which need genotypes.
+    pub sites_only: bool,
+}
+
+/// Whether a site can be part of an MNP: base REF and base ALT alleles only, and
+/// not a `--sites` panel site (MNP records carry no `FORCED` flag).
+fn mnp_candidate(vp: &VariablePosition) -> bool {
+    matches!(vp.ref_base, b'A' | b'C' | b'G' | b'T') && !vp.alt_bases.contains(&b'-') && !vp.forced
+}
+
+/// Whether adjacent sites `va` and `vb`, with genotype rows `ga` and `gb`, co-vary:
+/// every sample called at both carries either the reference or a non-reference
+/// base at both, and at least one carries non-reference bases at both.
+pub fn mnp_pair(ga: &[u8], gb: &[u8], va: &VariablePosition, vb: &VariablePosition) -> bool {
+    let is_base = |b: u8| matches!(b, b'A' | b'C' | b'G' | b'T');
+    if vb.index != va.index + 1 || !mnp_candidate(va) || !mnp_candidate(vb) { return false; }
+    let mut shared = false;
+    for (&ca, &cb) in ga.iter().zip(gb.iter()) {
+        if !is_base(ca) || !is_base(cb) { continue; }
+        let (alt_a, alt_b) = (ca != va.ref_base, cb != vb.ref_base);
+        if alt_a != alt_b { return false; }
+        shared |= alt_a;
+    }
+    shared
+}
+
+/// Group variable positions into runs of adjacent columns whose per-sample
+/// haplotypes co-vary pairwise (see `mnp_pair`). Sites for which `annotated`
+/// holds stay on their own (MNP records carry no `CSQ`). Returns inclusive
+/// `(first, last)` index ranges.
+pub fn mnp_groups(
+    vcf_geno: &[u8], num_samples: usize, var_positions: &[VariablePosition], annotated: impl Fn(usize) -> bool,
+) -> Vec<(usize, usize)> {
+    let row = |i: usize| &vcf_geno[i * num_samples..(i + 1) * num_samples];
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for vi in 0..var_positions.len() {
+        match groups.last_mut() {
+            Some(g) if !annotated(g.1) && !annotated(vi)
+                && mnp_pair(row(g.1), row(vi), &var_positions[g.1], &var_positions[vi]) => g.1 = vi,
+            _ => groups.push((vi, vi)),
+        }
+    }
+    groups
+}
 
 /// Write VCF output from genotype matrix and variable positions.
 pub fn write_vcf(
     vcf_geno: &[u8], num_samples: usize, var_positions: &[VariablePosition],
-    vcf_path: &str, records: &[FastaRecord], seq_length: usize,
+    vcf_path: &str, records: &[FastaRecord], seq_length: usize, opts: &VcfOptions,
 ) -> io::Result<()> {
-    let out = File::create(vcf_path).map_err(|e| io::Error::new(e.kind(),
-        format!("Cannot create VCF '{}': {}", vcf_path, e)))?;
-    let mut w = BufWriter::with_capacity(4 * 1024 * 1024, out);
-
-    // Header
-    writeln!(w, "##fileformat=VCFv4.2")?;
-    writeln!(w, "##source=snpick v{}", env!("CARGO_PKG_VERSION"))?;
-    writeln!(w, "##reference=first_sequence")?;
-    writeln!(w, "##contig=<ID=1,length={}>", seq_length)?;
-    writeln!(w, "##INFO=<ID=NS,Number=1,Type=Integer,Description=\"Number of Samples With Data\">")?;
-    writeln!(w, "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">")?;
-    write!(w, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT")?;
-    for rec in records {
-        write!(w, "\t")?;
-        w.write_all(rec.id)?;
+    debug_assert_eq!(num_samples, records.len());
+    let mut vw = VcfWriter::create(vcf_path, records, seq_length, var_positions, opts)?;
+    vw.write_sites(vcf_geno, 0..var_positions.len())?;
+    vw.finish()
+}
+
+/// Incremental VCF writer: the header is written on creation and data rows are
+/// appended per block of consecutive variable positions, each with its own
+/// block-local genotype matrix, so the full matrix never has to be held at once.
+pub struct VcfWriter<'a> {
+    w: BufWriter<File>,
+    num_samples: usize,
+    var_positions: &'a [VariablePosition],
+    opts: &'a VcfOptions<'a>,
+    /// Next indel record to write.
+    ii: usize,
+}
+
+impl<'a> VcfWriter<'a> {
+    /// Create the VCF and write its header. With `merge_mnps`, the `MNP` flag is declared.
+    pub fn create(
+        vcf_path: &str, records: &[FastaRecord], seq_length: usize,
+        var_positions: &'a [VariablePosition], opts: &'a VcfOptions<'a>,
+    ) -> io::Result<Self> {
+        let VcfOptions { annotations, indels, merge_mnps, sites_only, .. } = *opts;
+        debug_assert!(!sites_only || (indels.is_none() && !merge_mnps));
+
+        let out = File::create(vcf_path).map_err(|e| io::Error::new(e.kind(),
+            format!("Cannot create VCF '{}': {}", vcf_path, e)))?;
+        let mut w = BufWriter::with_capacity(4 * 1024 * 1024, out);
+
+        // Header
+        writeln!(w, "##fileformat=VCFv4.2")?;
+        writeln!(w, "##source=snpick v{}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(w, "##reference=first_sequence")?;
+        writeln!(w, "##contig=<ID=1,length={}>", seq_length)?;
+        writeln!(w, "##INFO=<ID=NS,Number=1,Type=Integer,Description=\"Number of Samples With Data\">")?;
+        writeln!(w, "##INFO=<ID=AC,Number=A,Type=Integer,Description=\"Allele count in genotypes, for each ALT allele\">")?;
+        writeln!(w, "##INFO=<ID=AN,Number=1,Type=Integer,Description=\"Total number of alleles in called genotypes\">")?;
+        writeln!(w, "##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele frequency, for each ALT allele (AC/AN)\">")?;
+        writeln!(w, "##INFO=<ID=NMISS,Number=1,Type=Integer,Description=\"Number of samples with a missing (N or IUPAC) call\">")?;
+        writeln!(w, "##INFO=<ID=NGAP,Number=1,Type=Integer,Description=\"Number of samples with a gap\">")?;
+        if annotations.is_some() {
+            writeln!(w, "##INFO=<ID=CSQ,Number=.,Type=String,Description=\"Consequence annotations from GFF3 (table 11). \
+                Format: Allele|Gene|Strand|CodonPos|Codons|AminoAcids|Consequence\">")?;
+        }
+        if merge_mnps {
+            writeln!(w, "##INFO=<ID=MNP,Number=0,Type=Flag,Description=\"Adjacent co-varying sites merged into one record\">")?;
+        }
+        if indels.is_some_and(|i| !i.is_empty()) {
+            writeln!(w, "##INFO=<ID=INDEL,Number=0,Type=Flag,Description=\"Indel called from a gap run\">")?;
+        }
+        if var_positions.iter().any(|vp| vp.forced) {
+            writeln!(w, "##INFO=<ID=FORCED,Number=0,Type=Flag,Description=\"Site requested with --sites\">")?;
+        }
+        if sites_only {
+            writeln!(w, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO")?;
+        } else {
+            writeln!(w, "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">")?;
+            write!(w, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT")?;
+            for rec in records {
+                write!(w, "\t")?;
+                w.write_all(rec.id)?;
+            }
+            writeln!(w)?;
+        }
+
+        Ok(VcfWriter { w, num_samples: records.len(), var_positions, opts, ii: 0 })
     }
-    writeln!(w)?;
-
-    // Data rows
-    let mut lut = [255u8; 256];
-    for (vi, vp) in var_positions.iter().enumerate() {
-        let alt: String = vp.alt_bases.iter()
-            .map(|&b| if b == b'-' { "*".to_string() } else { (b as char).to_string() })
-            .collect::<Vec<_>>().join(",");
-
-        // Build allele → index LUT for this position
-        lut[vp.ref_base as usize] = 0;
-        for (i, &ab) in vp.alt_bases.iter().enumerate() {
-            lut[ab as usize] = (i + 1) as u8;
+
+    /// Write the rows of `var_positions[block]` from the block's site-major
+    /// genotype matrix (`vcf_geno[(vi - block.start) * num_samples + si]`).
+    ///
+    /// Blocks must be written in order and must not split a run of adjacent
+    /// columns when merging MNPs.
+    pub fn write_sites(&mut self, vcf_geno: &[u8], block: Range<usize>) -> io::Result<()> {
+        let VcfOptions { annotations, indels, merge_mnps, split_multiallelic, sites_only } = *self.opts;
+        let (num_samples, first) = (self.num_samples, block.start);
+        let sites = &self.var_positions[block];
+        let w = &mut self.w;
+        let groups: Vec<(usize, usize)> = if merge_mnps {
+            mnp_groups(vcf_geno, num_samples, sites, |vi| annotations.is_some_and(|an| an.sites[first + vi].is_some()))
+        } else {
+            (0..sites.len()).map(|vi| (vi, vi)).collect()
+        };
+
+        // Data rows; indel records are interleaved by their anchor column
+        let mut lut = [255u8; 256];
+        let indel_mode = indels.is_some();
+        let indels = indels.unwrap_or(&[]);
+        let mut gts: Vec<Option<usize>> = Vec::with_capacity(num_samples);
+        for &(vi, last) in &groups {
+            let vp = &sites[vi];
+            while self.ii < indels.len() && indels[self.ii].column < vp.index {
+                write_indel(w, &indels[self.ii])?;
+                self.ii += 1;
+            }
+            if last > vi {
+                write_mnp(w, vcf_geno, num_samples, &sites[vi..=last], vi, split_multiallelic)?;
+                continue;
+            }
+            let filtered: VariablePosition;
+            let vp = if indel_mode {
+                if vp.ref_base == b'-' { continue; }
+                let alt_bases: Vec<u8> = vp.alt_bases.iter().copied().filter(|&b| b != b'-').collect();
+                if alt_bases.is_empty() { continue; }
+                filtered = VariablePosition { alt_bases, ..*vp };
+                &filtered
+            } else { vp };
+            let allele = |b: u8| if b == b'-' { "*".to_string() } else { (b as char).to_string() };
+
+            // Build allele → index LUT for this position (an all-missing forced site has REF N)
+            if vp.ref_base != b'N' { lut[vp.ref_base as usize] = 0; }
+            for (i, &ab) in vp.alt_bases.iter().enumerate() {
+                lut[ab as usize] = (i + 1) as u8;
+            }
+            // `.` calls are the gap allele too
+            lut[b'.' as usize] = lut[b'-' as usize];
+            gts.clear();
+            if !sites_only {
+                let row = vi * num_samples;
+                gts.extend(vcf_geno[row..row + num_samples].iter()
+                    .map(|&b| match lut[b as usize] { 255 => None, idx => Some(idx as usize) }));
+            }
+            let an = vp.calls.of(vp.ref_base) + vp.alt_bases.iter().map(|&b| vp.calls.of(b)).sum::<usize>();
+            // Without gap alleles, gap calls are missing GTs and not counted in NS
+            let ns = if indel_mode { gts.iter().flatten().count() } else { vp.ns };
+            let csq = annotations.and_then(|an| an.sites[first + vi].as_ref()
+                .map(|a| csq_field(a, &sites[vi], &an.cds)));
+
+            // One row per ALT when splitting, otherwise one row with all ALTs
+            let splits: Vec<Option<usize>> = if split_multiallelic && vp.alt_bases.len() > 1 {
+                (1..=vp.alt_bases.len()).map(Some).collect()
+            } else { vec![None] };
+            for k in splits {
+                let alts: &[u8] = match k { Some(k) => &vp.alt_bases[k - 1..k], None => &vp.alt_bases };
+                let alt = alts.iter().map(|&b| allele(b)).collect::<Vec<_>>().join(",");
+                let alt = if alt.is_empty() { ".".to_string() } else { alt };
+                let ac: Vec<usize> = alts.iter().map(|&b| vp.calls.of(b)).collect();
+                write!(w, "1\t{}\t.\t{}\t{}\t.\tPASS\tNS={};{};NMISS={};NGAP={}",
+                    vp.index + 1, vp.ref_base as char, alt, ns, allele_info(&ac, an), vp.calls.missing, vp.calls.gap)?;
+                if vp.forced { write!(w, ";FORCED")?; }
+                if let Some(ref csq) = csq {
+                    let csq: Vec<&str> = csq.split(',')
+                        .filter(|e| alts.iter().any(|&b| e.starts_with(&allele(b)))).collect();
+                    write!(w, ";CSQ={}", csq.join(","))?;
+                }
+                if sites_only { writeln!(w)?; continue; }
+                write!(w, "\tGT")?;
+                write_gts(w, &gts, k)?;
+            }
+
+            // Reset LUT entries
+            lut[vp.ref_base as usize] = 255;
+            for &ab in &vp.alt_bases { lut[ab as usize] = 255; }
+            lut[b'.' as usize] = 255;
         }
+        Ok(())
+    }
+
+    /// Write the remaining indel records and flush.
+    pub fn finish(mut self) -> io::Result<()> {
+        for rec in &self.opts.indels.unwrap_or(&[])[self.ii..] { write_indel(&mut self.w, rec)?; }
+        self.w.flush()
+    }
+}
+
+/// Write an MNP record for the adjacent sites `sites`, the first at index `first`.
+fn write_mnp<W: Write>(
+    w: &mut W, vcf_geno: &[u8], num_samples: usize, sites: &[VariablePosition], first: usize,
+    split_multiallelic: bool,
+) -> io::Result<()> {
+    let ref_allele: Vec<u8> = sites.iter().map(|vp| vp.ref_base).collect();
+    let mut alts: Vec<Vec<u8>> = Vec::new();
+    let mut gts: Vec<Option<usize>> = Vec::with_capacity(num_samples);
+    for si in 0..num_samples {
+        let hap: Vec<u8> = (0..sites.len()).map(|k| vcf_geno[(first + k) * num_samples + si]).collect();
+        gts.push(if !hap.iter().all(|b| matches!(b, b'A' | b'C' | b'G' | b'T')) { None }
+            else if hap == ref_allele { Some(0) }
+            else {
+                match alts.iter().position(|a| *a == hap) {
+                    Some(i) => Some(i + 1),
+                    None => { alts.push(hap); Some(alts.len()) }
+                }
+            });
+    }
+    let alt: Vec<String> = alts.iter().map(|a| String::from_utf8_lossy(a).into_owned()).collect();
+    let ns = gts.iter().flatten().count();
+    // Uncalled samples: a gap anywhere in the run counts as a gap, otherwise as missing
+    let ngap = (0..num_samples).filter(|&si| (0..sites.len()).any(|k| is_gap(vcf_geno[(first + k) * num_samples + si]))).count();
+    let nmiss = num_samples - ns - ngap;
+    let splits: Vec<Option<usize>> = if split_multiallelic && alt.len() > 1 {
+        (1..=alt.len()).map(Some).collect()
+    } else { vec![None] };
+    for k in splits {
+        let (alt, ac) = match k {
+            Some(k) => (alt[k - 1].clone(), vec![gts.iter().filter(|&&g| g == Some(k)).count()]),
+            None => (alt.join(","), (1..=alt.len()).map(|i| gts.iter().filter(|&&g| g == Some(i)).count()).collect()),
+        };
+        write!(w, "1\t{}\t.\t{}\t{}\t.\tPASS\tNS={};{};NMISS={};NGAP={};MNP\tGT", sites[0].index + 1,
+            String::from_utf8_lossy(&ref_allele), alt, ns, allele_info(&ac, ns), nmiss, ngap)?;
+        write_gts(w, &gts, k)?;
+    }
+    Ok(())
+}
 
-        write!(w, "1\t{}\t.\t{}\t{}\t.\tPASS\tNS={}\tGT",
-            vp.index + 1, vp.ref_base as char, alt, vp.ns)?;
+/// `AC`, `AN` and `AF` INFO values for ALT counts `ac` out of `an` called alleles
+/// (only `AN` for a site without ALT alleles).
+fn allele_info(ac: &[usize], an: usize) -> String {
+    if ac.is_empty() { return format!("AN={}", an); }
+    let join = |v: Vec<String>| v.join(",");
+    let af = ac.iter().map(|&c| if an == 0 { ".".to_string() }
+        else { ((c as f64 / an as f64 * 1e4).round() / 1e4).to_string() }).collect();
+    format!("AC={};AN={};AF={}", join(ac.iter().map(|c| c.to_string()).collect()), an, join(af))
+}
 
-        let row = vi * num_samples;
-        for si in 0..num_samples {
-            let idx = lut[vcf_geno[row + si] as usize];
-            if idx == 255 { write!(w, "\t.")?; } else { write!(w, "\t{}", idx)?; }
+/// Write the GT columns; with `split = Some(k)`, ALT `k` becomes `1` and the
+/// other ALTs `0` (as `bcftools norm -m-`).
+fn write_gts<W: Write>(w: &mut W, gts: &[Option<usize>], split: Option<usize>) -> io::Result<()> {
+    for gt in gts {
+        match (gt, split) {
+            (None, _) => write!(w, "\t.")?,
+            (Some(g), Some(k)) => write!(w, "\t{}", usize::from(*g == k))?,
+            (Some(g), None) => write!(w, "\t{}", g)?,
         }
-        writeln!(w)?;
+    }
+    writeln!(w)
+}
+
+fn write_indel<W: Write>(w: &mut W, rec: &IndelRecord) -> io::Result<()> {
+    let ns = rec.genotypes.iter().flatten().count();
+    let ac = rec.genotypes.iter().filter(|&&g| g == Some(1)).count();
+    write!(w, "1\t{}\t.\t{}\t{}\t.\tPASS\tNS={};{};INDEL\tGT", rec.column + 1,
+        String::from_utf8_lossy(&rec.ref_allele), String::from_utf8_lossy(&rec.alt_allele), ns,
+        allele_info(&[ac], ns))?;
+    for gt in &rec.genotypes {
+        match gt {
+            Some(g) => write!(w, "\t{}", g)?,
+            None => write!(w, "\t.")?,
+        }
+    }
+    writeln!(w)
+}
+
+/// Write a genotype-free variant table from the pass 2 call counts.
+///
+/// Columns: position, ref_position, ref, alt, ref_count, alt_count (per ALT,
+/// comma-separated), missing, gap. Gaps are only an allele (`-`) with `-g`.
+pub fn write_variant_table(var_positions: &[VariablePosition], path: &str) -> io::Result<()> {
+    let out = File::create(path).map_err(|e| io::Error::new(e.kind(),
+        format!("Cannot create variant table '{}': {}", path, e)))?;
+    let mut w = BufWriter::new(out);
 
-        // Reset LUT entries
-        lut[vp.ref_base as usize] = 255;
-        for &ab in &vp.alt_bases { lut[ab as usize] = 255; }
+    writeln!(w, "position\tref_position\tref\talt\tref_count\talt_count\tmissing\tgap")?;
+    for vp in var_positions {
+        let rp = vp.ref_pos.map_or(".".to_string(), |p| (p + 1).to_string());
+        let alt: Vec<String> = vp.alt_bases.iter().map(|&b| (b as char).to_string()).collect();
+        let ac: Vec<String> = vp.alt_bases.iter().map(|&b| vp.calls.of(b).to_string()).collect();
+        let (alt, ac) = if alt.is_empty() { (".".to_string(), ".".to_string()) } else { (alt.join(","), ac.join(",")) };
+        writeln!(w, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", vp.index + 1, rp, vp.ref_base as char, alt,
+            vp.calls.of(vp.ref_base), ac, vp.calls.missing, vp.calls.gap)?;
     }
 
     w.flush()